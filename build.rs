@@ -0,0 +1,47 @@
+use base64::{engine::general_purpose, Engine as _};
+use sha2::{Digest, Sha384};
+use std::fs;
+use std::path::Path;
+
+// The ALTCHA widget is vendored into static/ by scripts/vendor-altcha.sh and
+// embedded in the binary when present. It must match the hash pinned in
+// static/ALTCHA_INTEGRITY.
+fn main() {
+    println!("cargo::rerun-if-changed=static/ALTCHA_VERSION");
+    println!("cargo::rerun-if-changed=static/ALTCHA_INTEGRITY");
+    println!("cargo::rerun-if-changed=static/altcha.min.js");
+    println!("cargo::rustc-check-cfg=cfg(altcha_widget)");
+
+    let version = fs::read_to_string("static/ALTCHA_VERSION")
+        .expect("static/ALTCHA_VERSION pins the ALTCHA widget version");
+    println!("cargo::rustc-env=ALTCHA_WIDGET_VERSION={}", version.trim());
+
+    if !Path::new("static/altcha.min.js").exists() {
+        println!(
+            "cargo::warning=static/altcha.min.js is missing; run scripts/vendor-altcha.sh \
+             before building a release"
+        );
+        return;
+    }
+
+    let widget = fs::read("static/altcha.min.js").expect("static/altcha.min.js is readable");
+    let actual = format!(
+        "sha384-{}",
+        general_purpose::STANDARD.encode(Sha384::digest(&widget))
+    );
+    let pinned = fs::read_to_string("static/ALTCHA_INTEGRITY").unwrap_or_else(|_| {
+        panic!(
+            "static/ALTCHA_INTEGRITY is missing, so static/altcha.min.js ({}) cannot be \
+             checked; review the widget and run scripts/vendor-altcha.sh --pin",
+            actual
+        )
+    });
+    if pinned.trim() != actual {
+        panic!(
+            "static/altcha.min.js does not match static/ALTCHA_INTEGRITY: expected {}, got {}",
+            pinned.trim(),
+            actual
+        );
+    }
+    println!("cargo::rustc-cfg=altcha_widget");
+}
@@ -0,0 +1,31 @@
+//! Untrusted `altcha` fields: decoding, validation and, for anything that
+//! passes, solution checking. Valid payloads are re-signed so the checks
+//! behind the signature get exercised too.
+#![no_main]
+
+use altcha_server::challenge::{sign_challenge, verify_solution, AltchaPayload};
+use altcha_server::keyring::Key;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let key = Key::new("fuzz", "fuzz-secret-fuzz-secret-fuzz-secret");
+
+    if let Ok(encoded) = std::str::from_utf8(data) {
+        let _ = AltchaPayload::decode(encoded);
+    }
+
+    let Ok(mut payload) = AltchaPayload::from_json(data) else {
+        return;
+    };
+    let _ = verify_solution(&payload, std::slice::from_ref(&key));
+
+    if let Ok(algorithm) = payload.algorithm.parse() {
+        payload.signature = sign_challenge(
+            algorithm,
+            &payload.challenge,
+            &payload.salt,
+            key.challenge_key(),
+        );
+        let _ = verify_solution(&payload, std::slice::from_ref(&key));
+    }
+});
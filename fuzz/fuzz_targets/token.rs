@@ -0,0 +1,18 @@
+//! Untrusted verification cookies, in every format the codec accepts.
+#![no_main]
+
+use altcha_server::keyring::Key;
+use altcha_server::token::{verify_token, TokenCodec};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(token) = std::str::from_utf8(data) else {
+        return;
+    };
+    let keys = [Key::new("fuzz", "fuzz-secret-fuzz-secret-fuzz-secret")];
+    // Accept legacy tokens so their parser is reached as well
+    let codec = TokenCodec::new(Some(u64::MAX));
+
+    let _ = codec.open(token, &keys);
+    let _ = verify_token(&codec, token, "192.0.2.1", "fuzz", "example.org", &keys);
+});
@@ -5,9 +5,11 @@
 
 DAEMON_NAME="altcha-server"
 DAEMON_PATH="/usr/local/bin/altcha-server"
+CONFIG_FILE="/etc/altcha/altcha.toml"
 PID_FILE="/var/run/altcha-server.pid"
 LOG_FILE="/var/log/altcha-server.log"
 DAEMON_USER="www"
+HEALTH_URL="http://127.0.0.1:8080/healthz"
 
 # Check if daemon exists
 if [ ! -x "$DAEMON_PATH" ]; then
@@ -32,6 +34,15 @@ is_running() {
     fi
 }
 
+# Function to check that the server answers its liveness probe
+is_healthy() {
+    if command -v curl >/dev/null 2>&1; then
+        curl -fsS -o /dev/null --max-time 5 "$HEALTH_URL"
+    else
+        ftp -V -o /dev/null "$HEALTH_URL" >/dev/null 2>&1
+    fi
+}
+
 # Function to start the daemon
 start_daemon() {
     if is_running; then
@@ -46,7 +57,7 @@ start_daemon() {
     chown "$DAEMON_USER:$DAEMON_USER" "$LOG_FILE"
     
     # Start the daemon
-    su -s /bin/sh "$DAEMON_USER" -c "nohup $DAEMON_PATH >> $LOG_FILE 2>&1 & echo \$!" > "$PID_FILE"
+    su -s /bin/sh "$DAEMON_USER" -c "nohup $DAEMON_PATH --config $CONFIG_FILE >> $LOG_FILE 2>&1 & echo \$!" > "$PID_FILE"
     
     # Wait a moment and check if it started successfully
     sleep 2
@@ -73,8 +84,9 @@ stop_daemon() {
     pid=$(get_pid)
     kill "$pid"
     
-    # Wait for process to stop
-    for i in $(seq 1 10); do
+    # Wait for process to stop; it drains in-flight requests for up to
+    # server.shutdown_timeout_secs first
+    for i in $(seq 1 15); do
         if ! kill -0 "$pid" 2>/dev/null; then
             break
         fi
@@ -103,8 +115,12 @@ restart_daemon() {
 # Function to show status
 show_status() {
     if is_running; then
-        echo "ALTCHA server is running (PID: $(get_pid))"
-        return 0
+        if is_healthy; then
+            echo "ALTCHA server is running (PID: $(get_pid))"
+            return 0
+        fi
+        echo "ALTCHA server is running (PID: $(get_pid)) but not answering $HEALTH_URL"
+        return 1
     else
         echo "ALTCHA server is not running"
         return 1
@@ -0,0 +1,43 @@
+#!/bin/sh
+
+# Vendor the ALTCHA widget
+# Usage: vendor-altcha.sh [--pin]
+#
+# Downloads the widget version pinned in static/ALTCHA_VERSION from the npm
+# registry into static/altcha.min.js, where the next build embeds it. The
+# download must match the sha384 hash pinned in static/ALTCHA_INTEGRITY.
+#
+# After changing static/ALTCHA_VERSION, review the new release and run with
+# --pin to record its hash instead; commit both files together.
+
+set -eu
+
+cd "$(dirname "$0")/.."
+PIN=0
+if [ "${1:-}" = "--pin" ]; then
+    PIN=1
+fi
+VERSION=$(cat static/ALTCHA_VERSION)
+TMP_DIR=$(mktemp -d)
+trap 'rm -rf "$TMP_DIR"' EXIT
+
+curl -fsSL "https://registry.npmjs.org/altcha/-/altcha-${VERSION}.tgz" -o "$TMP_DIR/altcha.tgz"
+tar -xzf "$TMP_DIR/altcha.tgz" -C "$TMP_DIR" package/dist/altcha.min.js
+WIDGET="$TMP_DIR/package/dist/altcha.min.js"
+INTEGRITY="sha384-$(openssl dgst -sha384 -binary "$WIDGET" | openssl base64 -A)"
+
+if [ "$PIN" = 1 ]; then
+    echo "$INTEGRITY" > static/ALTCHA_INTEGRITY
+    echo "Pinned altcha ${VERSION}: ${INTEGRITY}"
+elif [ ! -f static/ALTCHA_INTEGRITY ]; then
+    echo "Error: static/ALTCHA_INTEGRITY is missing; review altcha ${VERSION} and rerun with --pin" >&2
+    exit 1
+elif [ "$(cat static/ALTCHA_INTEGRITY)" != "$INTEGRITY" ]; then
+    echo "Error: altcha ${VERSION} does not match static/ALTCHA_INTEGRITY" >&2
+    echo "  expected $(cat static/ALTCHA_INTEGRITY)" >&2
+    echo "  got      ${INTEGRITY}" >&2
+    exit 1
+fi
+
+cp "$WIDGET" static/altcha.min.js
+echo "Vendored altcha ${VERSION}: static/altcha.min.js (${INTEGRITY})"
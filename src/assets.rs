@@ -0,0 +1,127 @@
+use axum::{
+    http::{header, HeaderValue},
+    response::{IntoResponse, Response},
+};
+use base64::{engine::general_purpose, Engine as _};
+use sha2::{Digest, Sha256, Sha384};
+use url::Url;
+
+/// Version of the vendored widget, from static/ALTCHA_VERSION.
+pub const WIDGET_VERSION: &str = env!("ALTCHA_WIDGET_VERSION");
+
+#[cfg(altcha_widget)]
+const WIDGET_JS: Option<&[u8]> = Some(include_bytes!("../static/altcha.min.js"));
+#[cfg(not(altcha_widget))]
+const WIDGET_JS: Option<&[u8]> = None;
+
+const PAGE_JS: &[u8] = include_bytes!("../static/challenge.js");
+
+/// Only the challenge page itself may run scripts, and only ones we serve.
+/// The widget injects its own styles and runs its solver in a blob: worker.
+const CONTENT_SECURITY_POLICY: &str = "default-src 'none'; script-src 'self'; \
+    style-src 'self' 'unsafe-inline'; img-src 'self' data:; connect-src 'self'; \
+    worker-src 'self' blob:; base-uri 'none'; frame-ancestors 'none'";
+
+/// The challenge page policy. The verification form posts to this server,
+/// which then redirects to `return_to`; browsers check that redirect against
+/// `form-action` too, so its origin is allowed as well.
+pub fn content_security_policy(return_to: &str) -> String {
+    let origin = Url::parse(return_to)
+        .map(|url| url.origin().ascii_serialization())
+        .unwrap_or_default();
+    format!("{}; form-action 'self' {}", CONTENT_SECURITY_POLICY, origin)
+        .trim_end()
+        .to_string()
+}
+
+/// Where assets are served. Under `/api/altcha/` so the proxy already routes
+/// it here and it cannot shadow a protected site's own `/static/`.
+pub const STATIC_PREFIX: &str = "/api/altcha/static/";
+
+/// A file served from [`STATIC_PREFIX`]. Every URL is versioned or
+/// content-hashed, so browsers may cache it forever.
+#[derive(Debug)]
+pub struct Asset {
+    /// URL path, `/api/altcha/static/<name>`
+    pub url: String,
+    /// Subresource Integrity value for `integrity="..."`
+    pub integrity: String,
+    content_type: &'static str,
+    body: &'static [u8],
+}
+
+impl Asset {
+    fn new(name: String, content_type: &'static str, body: &'static [u8]) -> Self {
+        Self {
+            url: format!("{}{}", STATIC_PREFIX, name),
+            integrity: format!(
+                "sha384-{}",
+                general_purpose::STANDARD.encode(Sha384::digest(body))
+            ),
+            content_type,
+            body,
+        }
+    }
+
+    pub fn response(&self) -> Response {
+        let mut response = self.body.into_response();
+        let headers = response.headers_mut();
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static(self.content_type),
+        );
+        headers.insert(
+            header::CACHE_CONTROL,
+            HeaderValue::from_static("public, max-age=31536000, immutable"),
+        );
+        headers.insert(
+            header::X_CONTENT_TYPE_OPTIONS,
+            HeaderValue::from_static("nosniff"),
+        );
+        response
+    }
+}
+
+/// The assets compiled into the binary.
+#[derive(Debug)]
+pub struct StaticAssets {
+    widget: Option<Asset>,
+    page_script: Asset,
+}
+
+impl StaticAssets {
+    pub fn embedded() -> Self {
+        let page_hash = hex::encode(&Sha256::digest(PAGE_JS)[..8]);
+        Self {
+            widget: WIDGET_JS.map(|body| {
+                Asset::new(
+                    format!("altcha-{}.min.js", WIDGET_VERSION),
+                    "text/javascript; charset=utf-8",
+                    body,
+                )
+            }),
+            page_script: Asset::new(
+                format!("challenge-{}.js", page_hash),
+                "text/javascript; charset=utf-8",
+                PAGE_JS,
+            ),
+        }
+    }
+
+    /// The ALTCHA widget, if it was vendored before building.
+    pub fn widget(&self) -> Option<&Asset> {
+        self.widget.as_ref()
+    }
+
+    pub fn page_script(&self) -> &Asset {
+        &self.page_script
+    }
+
+    /// The asset served as `/api/altcha/static/<name>`.
+    pub fn get(&self, name: &str) -> Option<&Asset> {
+        self.widget
+            .iter()
+            .chain([&self.page_script])
+            .find(|asset| asset.url.strip_prefix(STATIC_PREFIX) == Some(name))
+    }
+}
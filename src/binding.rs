@@ -0,0 +1,141 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::client_ip::subnet_key;
+
+/// How tightly a verification token is tied to the client it was issued to.
+/// The policy travels inside the token, so a token is always checked with
+/// the rule it was issued under, even if the configuration changes later.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BindingPolicy {
+    /// The exact client IP address
+    #[default]
+    Exact,
+    /// The client's IPv4 /24 or IPv6 /64
+    Prefix,
+    /// Not bound to the client at all; only the domain and expiry apply
+    None,
+    /// A hash of the User-Agent header, for clients that hop addresses
+    UserAgent,
+}
+
+impl BindingPolicy {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            BindingPolicy::Exact => "exact",
+            BindingPolicy::Prefix => "prefix",
+            BindingPolicy::None => "none",
+            BindingPolicy::UserAgent => "user_agent",
+        }
+    }
+
+    /// The value recorded in a token issued under this policy, and compared
+    /// against when it is validated.
+    pub fn subject(self, client_ip: &str, user_agent: &str) -> String {
+        match self {
+            BindingPolicy::Exact => client_ip.to_string(),
+            BindingPolicy::Prefix => subnet_key(client_ip),
+            BindingPolicy::None => String::new(),
+            // The token is signed, so a plain hash is enough to keep the
+            // header itself out of the cookie
+            BindingPolicy::UserAgent => hex::encode(&Sha256::digest(user_agent.as_bytes())[..16]),
+        }
+    }
+}
+
+impl fmt::Display for BindingPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for BindingPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "exact" => Ok(BindingPolicy::Exact),
+            "prefix" => Ok(BindingPolicy::Prefix),
+            "none" => Ok(BindingPolicy::None),
+            "user_agent" => Ok(BindingPolicy::UserAgent),
+            other => Err(format!("unknown binding policy {:?}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BindingConfig {
+    /// Policy for sites that do not set their own `binding`
+    pub default: BindingPolicy,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::key;
+    use crate::token::{create_verification_token, verify_token, TokenCodec};
+
+    const DOMAIN: &str = "dataspace.princeton.edu";
+    const FIREFOX: &str = "Mozilla/5.0 (X11; Linux x86_64; rv:128.0) Gecko/20100101 Firefox/128.0";
+    const CURL: &str = "curl/8.5.0";
+
+    fn issue(binding: BindingPolicy, client_ip: &str, user_agent: &str) -> String {
+        create_verification_token(
+            &TokenCodec::new(None),
+            binding,
+            client_ip,
+            user_agent,
+            DOMAIN,
+            &key(),
+            60,
+        )
+        .unwrap()
+    }
+
+    fn accepts(token: &str, client_ip: &str, user_agent: &str) -> bool {
+        verify_token(
+            &TokenCodec::new(None),
+            token,
+            client_ip,
+            user_agent,
+            DOMAIN,
+            &[key()],
+        )
+        .is_ok()
+    }
+
+    #[test]
+    fn prefix_binding_accepts_the_same_subnet_only() {
+        let token = issue(BindingPolicy::Prefix, "192.0.2.10", FIREFOX);
+        assert!(accepts(&token, "192.0.2.200", FIREFOX));
+        assert!(!accepts(&token, "192.0.3.10", FIREFOX));
+
+        let token = issue(BindingPolicy::Prefix, "2001:db8:1:2::10", FIREFOX);
+        assert!(accepts(&token, "2001:db8:1:2:ffff::1", FIREFOX));
+        assert!(!accepts(&token, "2001:db8:1:3::10", FIREFOX));
+    }
+
+    #[test]
+    fn user_agent_binding_rejects_another_user_agent() {
+        let token = issue(BindingPolicy::UserAgent, "192.0.2.10", FIREFOX);
+        assert!(accepts(&token, "198.51.100.7", FIREFOX));
+        assert!(!accepts(&token, "192.0.2.10", CURL));
+    }
+
+    // Verification never consults the configured policy, so a token keeps
+    // the rule it was issued under when the configuration changes
+    #[test]
+    fn the_policy_in_the_token_outlives_a_configuration_change() {
+        let prefix = issue(BindingPolicy::Prefix, "192.0.2.10", FIREFOX);
+        let exact = issue(BindingPolicy::Exact, "192.0.2.10", FIREFOX);
+        let none = issue(BindingPolicy::None, "192.0.2.10", FIREFOX);
+
+        assert!(accepts(&prefix, "192.0.2.11", CURL));
+        assert!(!accepts(&exact, "192.0.2.11", FIREFOX));
+        assert!(accepts(&none, "203.0.113.5", CURL));
+    }
+}
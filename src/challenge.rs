@@ -0,0 +1,542 @@
+use base64::{engine::general_purpose, Engine as _};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha384, Sha512};
+use std::fmt;
+use std::str::FromStr;
+use tracing::{info, warn};
+
+use crate::crypto::{hmac_hex, verify_hmac_hex, MacError};
+use crate::keyring::Key;
+use crate::redact;
+use crate::unix_now;
+
+/// Proof-of-work hash functions supported by the ALTCHA widget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Algorithm {
+    #[default]
+    #[serde(rename = "SHA-256")]
+    Sha256,
+    #[serde(rename = "SHA-384")]
+    Sha384,
+    #[serde(rename = "SHA-512")]
+    Sha512,
+}
+
+impl Algorithm {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Algorithm::Sha256 => "SHA-256",
+            Algorithm::Sha384 => "SHA-384",
+            Algorithm::Sha512 => "SHA-512",
+        }
+    }
+
+    /// Digest size in bytes.
+    pub fn digest_len(self) -> usize {
+        match self {
+            Algorithm::Sha256 => 32,
+            Algorithm::Sha384 => 48,
+            Algorithm::Sha512 => 64,
+        }
+    }
+
+    /// Hex-encoded digest of `data`.
+    pub fn hash_hex(self, data: &[u8]) -> String {
+        match self {
+            Algorithm::Sha256 => hex::encode(Sha256::digest(data)),
+            Algorithm::Sha384 => hex::encode(Sha384::digest(data)),
+            Algorithm::Sha512 => hex::encode(Sha512::digest(data)),
+        }
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "SHA-256" => Ok(Algorithm::Sha256),
+            "SHA-384" => Ok(Algorithm::Sha384),
+            "SHA-512" => Ok(Algorithm::Sha512),
+            other => Err(format!("unsupported algorithm {:?}", other)),
+        }
+    }
+}
+
+/// Why a submitted solution was rejected.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SolutionError {
+    UnsupportedAlgorithm(String),
+    /// Not signed by any key in the keyring
+    InvalidSignature(MacError),
+    Expired,
+    /// The signed salt lacks a valid `expires` or `maxnumber` parameter
+    InvalidSalt,
+    /// The number is larger than the challenge's signed `maxnumber`
+    NumberOutOfRange,
+    /// The number does not hash to the challenge
+    PowMismatch,
+}
+
+impl SolutionError {
+    /// Machine-readable reason, see [`crate::error::AppError::code`].
+    pub fn code(&self) -> &'static str {
+        match self {
+            SolutionError::UnsupportedAlgorithm(_) => "unsupported_algorithm",
+            SolutionError::InvalidSignature(_) => "invalid_signature",
+            SolutionError::Expired => "expired",
+            SolutionError::InvalidSalt => "invalid_salt",
+            SolutionError::NumberOutOfRange => "number_out_of_range",
+            SolutionError::PowMismatch => "pow_mismatch",
+        }
+    }
+}
+
+impl fmt::Display for SolutionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SolutionError::UnsupportedAlgorithm(algorithm) => {
+                write!(f, "unsupported algorithm {:?}", algorithm)
+            }
+            SolutionError::InvalidSignature(e) => write!(f, "invalid challenge signature: {}", e),
+            SolutionError::Expired => write!(f, "challenge has expired"),
+            SolutionError::InvalidSalt => write!(f, "challenge salt is missing its parameters"),
+            SolutionError::NumberOutOfRange => write!(f, "number exceeds the challenge maxnumber"),
+            SolutionError::PowMismatch => write!(f, "proof of work does not match the challenge"),
+        }
+    }
+}
+
+impl std::error::Error for SolutionError {}
+
+#[derive(Serialize)]
+pub struct Challenge {
+    pub algorithm: Algorithm,
+    pub challenge: String,
+    pub maxnumber: u32,
+    pub salt: String,
+    pub signature: String,
+}
+
+/// Longest base64 `altcha` field accepted. A well-formed SHA-512 payload
+/// encodes to about 500 characters.
+pub const MAX_ENCODED_PAYLOAD_LEN: usize = 1024;
+
+/// Longest salt accepted: 32 hex characters plus the `expires` and
+/// `maxnumber` parameters.
+const MAX_SALT_LEN: usize = 128;
+
+/// Why a submitted `altcha` field was rejected before its solution was
+/// checked.
+#[derive(Debug, PartialEq, Eq)]
+pub enum PayloadError {
+    /// Longer than [`MAX_ENCODED_PAYLOAD_LEN`]
+    TooLarge(usize),
+    /// Not valid base64
+    Encoding(String),
+    /// Not a JSON object with the expected fields
+    Malformed(String),
+    UnsupportedAlgorithm(String),
+    /// Not lowercase hex of the algorithm's digest length
+    InvalidChallenge,
+    /// Not lowercase hex of an HMAC-SHA256
+    InvalidSignature,
+    /// Not a salt this server could have issued
+    InvalidSalt,
+    /// The number is larger than the salt's `maxnumber`
+    NumberOutOfRange,
+}
+
+impl PayloadError {
+    /// Machine-readable reason, see [`crate::error::AppError::code`].
+    pub fn code(&self) -> &'static str {
+        match self {
+            PayloadError::TooLarge(_) => "payload_too_large",
+            PayloadError::Encoding(_) => "invalid_encoding",
+            PayloadError::Malformed(_) => "invalid_payload",
+            PayloadError::UnsupportedAlgorithm(_) => "unsupported_algorithm",
+            PayloadError::InvalidChallenge => "invalid_challenge",
+            PayloadError::InvalidSignature => "malformed_signature",
+            PayloadError::InvalidSalt => "invalid_salt",
+            PayloadError::NumberOutOfRange => "number_out_of_range",
+        }
+    }
+}
+
+impl fmt::Display for PayloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PayloadError::TooLarge(len) => write!(
+                f,
+                "altcha field is {} bytes, the limit is {}",
+                len, MAX_ENCODED_PAYLOAD_LEN
+            ),
+            PayloadError::Encoding(reason) => write!(f, "altcha is not valid base64: {}", reason),
+            PayloadError::Malformed(reason) => write!(f, "invalid altcha payload: {}", reason),
+            PayloadError::UnsupportedAlgorithm(algorithm) => {
+                write!(f, "unsupported algorithm {:?}", algorithm)
+            }
+            PayloadError::InvalidChallenge => write!(f, "challenge is not a hex digest"),
+            PayloadError::InvalidSignature => write!(f, "signature is not a hex HMAC"),
+            PayloadError::InvalidSalt => write!(f, "salt is not in the expected format"),
+            PayloadError::NumberOutOfRange => write!(f, "number exceeds the salt maxnumber"),
+        }
+    }
+}
+
+impl std::error::Error for PayloadError {}
+
+/// A solution as submitted by the widget. Build one with
+/// [`AltchaPayload::decode`] or [`AltchaPayload::from_json`], which reject
+/// anything this server could not have issued before any hashing happens.
+#[derive(Deserialize)]
+pub struct AltchaPayload {
+    pub algorithm: String,
+    pub challenge: String,
+    pub number: u32,
+    pub salt: String,
+    pub signature: String,
+}
+
+impl AltchaPayload {
+    /// Decodes and validates the base64 `altcha` form field.
+    pub fn decode(encoded: &str) -> Result<Self, PayloadError> {
+        if encoded.len() > MAX_ENCODED_PAYLOAD_LEN {
+            return Err(PayloadError::TooLarge(encoded.len()));
+        }
+        let json = general_purpose::STANDARD
+            .decode(encoded)
+            .map_err(|e| PayloadError::Encoding(e.to_string()))?;
+        Self::from_json(&json)
+    }
+
+    /// Parses and validates a decoded payload.
+    pub fn from_json(json: &[u8]) -> Result<Self, PayloadError> {
+        let payload: Self =
+            serde_json::from_slice(json).map_err(|e| PayloadError::Malformed(e.to_string()))?;
+        payload.validate()?;
+        Ok(payload)
+    }
+
+    fn validate(&self) -> Result<(), PayloadError> {
+        let algorithm: Algorithm = self
+            .algorithm
+            .parse()
+            .map_err(|_| PayloadError::UnsupportedAlgorithm(self.algorithm.clone()))?;
+        if !is_lower_hex(&self.challenge, algorithm.digest_len() * 2) {
+            return Err(PayloadError::InvalidChallenge);
+        }
+        if !is_lower_hex(&self.signature, 64) {
+            return Err(PayloadError::InvalidSignature);
+        }
+        let max_number = parse_salt(&self.salt).ok_or(PayloadError::InvalidSalt)?;
+        if self.number > max_number {
+            return Err(PayloadError::NumberOutOfRange);
+        }
+        Ok(())
+    }
+}
+
+fn is_lower_hex(s: &str, len: usize) -> bool {
+    s.len() == len && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+fn is_decimal(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Checks that `salt` has the shape [`generate_salt`] gives it and returns
+/// its `maxnumber`.
+fn parse_salt(salt: &str) -> Option<u32> {
+    if salt.len() > MAX_SALT_LEN {
+        return None;
+    }
+    let (random, params) = salt.split_once('?')?;
+    let (expires, max_number) = params.split_once('&')?;
+    let expires = expires.strip_prefix("expires=")?;
+    let max_number = max_number.strip_prefix("maxnumber=")?;
+    if !is_lower_hex(random, 32) || !is_decimal(expires) || !is_decimal(max_number) {
+        return None;
+    }
+    expires.parse::<u64>().ok()?;
+    max_number.parse().ok()
+}
+
+pub fn generate_salt(expires: u64, max_number: u32) -> String {
+    let mut rng = rand::thread_rng();
+    let bytes: [u8; 16] = rng.gen();
+    // ALTCHA salt parameters ride along in query-string form after the random
+    // part; the whole salt is covered by the challenge hash and signature.
+    format!(
+        "{}?expires={}&maxnumber={}",
+        hex::encode(bytes),
+        expires,
+        max_number
+    )
+}
+
+fn salt_param<'a>(salt: &'a str, name: &str) -> Option<&'a str> {
+    let (_, params) = salt.split_once('?')?;
+    params
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+pub fn challenge_expires(salt: &str) -> Option<u64> {
+    salt_param(salt, "expires")?.parse().ok()
+}
+
+pub fn challenge_max_number(salt: &str) -> Option<u32> {
+    salt_param(salt, "maxnumber")?.parse().ok()
+}
+
+/// Hashes the salt with a random secret number. The number is the answer
+/// to the challenge, so it is dropped here and never returned or logged.
+pub fn generate_challenge(algorithm: Algorithm, salt: &str, max_number: u32) -> String {
+    // ALTCHA proof-of-work: generate a secret number and create challenge from it
+    let mut rng = rand::thread_rng();
+    let secret_number: u32 = rng.gen_range(0..max_number);
+
+    // Create challenge by hashing salt + secret_number
+    let work_data = format!("{}{}", salt, secret_number);
+    algorithm.hash_hex(work_data.as_bytes())
+}
+
+// The algorithm is part of the signed data so a client cannot swap in a
+// different hash function for a challenge it was issued.
+fn signed_data(algorithm: Algorithm, challenge: &str, salt: &str) -> String {
+    format!("{}{}{}", algorithm, challenge, salt)
+}
+
+/// Signs a challenge with a key derived for challenges only (see
+/// [`Key::challenge_key`]).
+pub fn sign_challenge(algorithm: Algorithm, challenge: &str, salt: &str, key: &[u8]) -> String {
+    hmac_hex(key, signed_data(algorithm, challenge, salt).as_bytes())
+}
+
+/// Checks a submitted solution against the keyring. Replay protection is
+/// left to the caller.
+pub fn verify_solution(payload: &AltchaPayload, keys: &[Key]) -> Result<(), SolutionError> {
+    let algorithm: Algorithm = payload
+        .algorithm
+        .parse()
+        .map_err(|_| SolutionError::UnsupportedAlgorithm(payload.algorithm.clone()))?;
+
+    // Verify signature against the active key and any previous keys still in
+    // rotation; challenges carry no key id, but there are only a few keys.
+    let signed = signed_data(algorithm, &payload.challenge, &payload.salt);
+    let mut signing_key = None;
+    for key in keys {
+        match verify_hmac_hex(key.challenge_key(), signed.as_bytes(), &payload.signature) {
+            Ok(()) => {
+                signing_key = Some(key);
+                break;
+            }
+            Err(MacError::Mismatch) => {}
+            Err(e) => return Err(SolutionError::InvalidSignature(e)),
+        }
+    }
+    let Some(signing_key) = signing_key else {
+        warn!(
+            algorithm = %algorithm,
+            keys = ?keys.iter().map(|key| key.id.as_str()).collect::<Vec<_>>(),
+            signature = %redact::salt(&payload.signature),
+            "Challenge signature mismatch"
+        );
+        return Err(SolutionError::InvalidSignature(MacError::Mismatch));
+    };
+
+    // The salt is signed, so its expiry can be trusted from here on. A
+    // challenge is valid strictly before `expires`, the same instant the
+    // replay store forgets it.
+    match challenge_expires(&payload.salt) {
+        Some(expires) if unix_now() < expires => {}
+        Some(expires) => {
+            warn!(
+                expires,
+                now = unix_now(),
+                salt = %redact::salt(&payload.salt),
+                "Challenge expired"
+            );
+            return Err(SolutionError::Expired);
+        }
+        None => {
+            warn!(salt = %redact::salt(&payload.salt), "Challenge salt has no valid expiry");
+            return Err(SolutionError::InvalidSalt);
+        }
+    }
+
+    // The difficulty is signed too; a number beyond it was not our challenge
+    match challenge_max_number(&payload.salt) {
+        Some(max_number) if payload.number <= max_number => {}
+        Some(max_number) => {
+            warn!(
+                number = %redact::solution(&payload.number.to_string()),
+                max_number,
+                "Solution number exceeds signed maxnumber"
+            );
+            return Err(SolutionError::NumberOutOfRange);
+        }
+        None => {
+            warn!(salt = %redact::salt(&payload.salt), "Challenge salt has no valid maxnumber");
+            return Err(SolutionError::InvalidSalt);
+        }
+    }
+
+    // Verify proof of work - hash must EQUAL the challenge (not start with)
+    let work_data = format!("{}{}", payload.salt, payload.number);
+    let hash_hex = algorithm.hash_hex(work_data.as_bytes());
+
+    // Check if hash exactly matches challenge
+    let challenge_met = hash_hex == payload.challenge;
+
+    info!(
+        algorithm = %algorithm,
+        kid = %signing_key.id,
+        salt = %redact::salt(&payload.salt),
+        number = %redact::solution(&payload.number.to_string()),
+        challenge = %redact::salt(&payload.challenge),
+        matches = challenge_met,
+        "Verifying solution"
+    );
+
+    if !challenge_met {
+        warn!(
+            hash = %redact::salt(&hash_hex),
+            challenge = %redact::salt(&payload.challenge),
+            "Proof of work failed"
+        );
+        return Err(SolutionError::PowMismatch);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::replay::{replay_key, MemoryReplayStore, ReplayStore};
+    use crate::test_support::{key, solved};
+
+    const NUMBER: u32 = 4242;
+
+    // A solved challenge signed by `key()` that expires at `expires`
+    fn solved_until(expires: u64) -> AltchaPayload {
+        solved(&generate_salt(expires, 50_000), NUMBER)
+    }
+
+    fn encode(payload: &serde_json::Value) -> String {
+        general_purpose::STANDARD.encode(payload.to_string())
+    }
+
+    fn good() -> serde_json::Value {
+        let payload = solved_until(unix_now() + 60);
+        serde_json::json!({
+            "algorithm": payload.algorithm,
+            "challenge": payload.challenge,
+            "number": payload.number,
+            "salt": payload.salt,
+            "signature": payload.signature,
+        })
+    }
+
+    // `good()` with `field` replaced
+    fn with(field: &str, value: serde_json::Value) -> String {
+        let mut payload = good();
+        payload[field] = value;
+        encode(&payload)
+    }
+
+    #[test]
+    fn payloads_are_accepted_or_rejected_with_their_code() {
+        let payload = AltchaPayload::decode(&encode(&good())).unwrap();
+        assert_eq!(verify_solution(&payload, &[key()]), Ok(()));
+
+        let salt = good()["salt"].as_str().unwrap().to_string();
+        let cases = [
+            ("A".repeat(MAX_ENCODED_PAYLOAD_LEN + 4), "payload_too_large"),
+            ("not base64!".to_string(), "invalid_encoding"),
+            (
+                encode(&serde_json::json!({"algorithm": "SHA-256"})),
+                "invalid_payload",
+            ),
+            (with("number", (-1).into()), "invalid_payload"),
+            (with("algorithm", "MD5".into()), "unsupported_algorithm"),
+            (with("challenge", "abc".into()), "invalid_challenge"),
+            (
+                with("challenge", "AB".repeat(32).into()),
+                "invalid_challenge",
+            ),
+            // A SHA-512 digest length for a SHA-256 challenge
+            (
+                with("challenge", "ab".repeat(64).into()),
+                "invalid_challenge",
+            ),
+            (
+                with("signature", "ab".repeat(31).into()),
+                "malformed_signature",
+            ),
+            (
+                with("signature", "xy".repeat(32).into()),
+                "malformed_signature",
+            ),
+            (with("salt", "short".into()), "invalid_salt"),
+            (
+                with("salt", salt.replace("maxnumber", "max").into()),
+                "invalid_salt",
+            ),
+            (with("salt", format!("{}&x=1", salt).into()), "invalid_salt"),
+            (
+                with(
+                    "salt",
+                    format!("{}{}", salt, "0".repeat(MAX_SALT_LEN)).into(),
+                ),
+                "invalid_salt",
+            ),
+            (with("number", 50_001.into()), "number_out_of_range"),
+        ];
+        for (encoded, code) in cases {
+            match AltchaPayload::decode(&encoded) {
+                Ok(_) => panic!("accepted a payload that should fail with {}", code),
+                Err(e) => assert_eq!(e.code(), code, "{}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn challenges_expire_when_the_replay_store_forgets_them() {
+        let keys = [key()];
+        let now = unix_now();
+
+        let store = MemoryReplayStore::new();
+
+        // Still valid, so the store must still hold it after one redemption
+        let live = solved_until(now + 60);
+        assert_eq!(verify_solution(&live, &keys), Ok(()));
+        let spent = replay_key(&live.salt, &live.signature);
+        let expires = challenge_expires(&live.salt).unwrap();
+        assert!(store.mark_spent(&spent, expires));
+        assert!(!store.mark_spent(&spent, expires));
+
+        // At `expires` the challenge is rejected and the store has let it go
+        let expired = solved_until(now);
+        assert_eq!(
+            verify_solution(&expired, &keys),
+            Err(SolutionError::Expired)
+        );
+        let spent = replay_key(&expired.salt, &expired.signature);
+        let expires = challenge_expires(&expired.salt).unwrap();
+        assert_eq!(expires, now);
+        assert!(store.mark_spent(&spent, expires));
+        assert!(store.mark_spent(&spent, expires));
+    }
+}
@@ -0,0 +1,293 @@
+use axum::http::HeaderMap;
+use ipnet::IpNet;
+use serde::Deserialize;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+/// The one header trusted proxies report the client address in. Every other
+/// forwarding header is ignored, since a proxy passes on whatever the client
+/// sent in the headers it does not set itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProxyHeader {
+    /// `X-Forwarded-For`, as nginx's `$proxy_add_x_forwarded_for` appends to
+    #[default]
+    XForwardedFor,
+    /// RFC 7239 `Forwarded`
+    Forwarded,
+    /// `X-Real-IP`, a single address set by the proxy
+    XRealIp,
+}
+
+impl ProxyHeader {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ProxyHeader::XForwardedFor => "x-forwarded-for",
+            ProxyHeader::Forwarded => "forwarded",
+            ProxyHeader::XRealIp => "x-real-ip",
+        }
+    }
+}
+
+impl fmt::Display for ProxyHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ProxyHeader {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "x-forwarded-for" => Ok(ProxyHeader::XForwardedFor),
+            "forwarded" => Ok(ProxyHeader::Forwarded),
+            "x-real-ip" => Ok(ProxyHeader::XRealIp),
+            other => Err(format!("unknown proxy header {:?}", other)),
+        }
+    }
+}
+
+/// Networks whose requests may tell us who the real client is, i.e. our own
+/// reverse proxies, and the header they tell us in. Forwarding headers from
+/// anyone else are ignored.
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+    networks: Vec<IpNet>,
+    header: ProxyHeader,
+}
+
+impl TrustedProxies {
+    /// Parses CIDR ranges; a bare address is taken as a single host.
+    pub fn parse(entries: &[String], header: ProxyHeader) -> Result<Self, String> {
+        let networks = entries
+            .iter()
+            .map(|entry| {
+                let entry = entry.trim();
+                entry
+                    .parse::<IpNet>()
+                    .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|_| format!("{:?} is not an IP address or CIDR range", entry))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { networks, header })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = canonical(ip);
+        self.networks.iter().any(|net| net.contains(&ip))
+    }
+}
+
+/// Works out the real client address for a request.
+///
+/// When the socket peer is not a trusted proxy, the peer is the client and
+/// every forwarding header is ignored. Otherwise the configured
+/// [`ProxyHeader`] is walked from the right, skipping trusted proxies; the
+/// first untrusted hop is the client. Entries to the left of it were supplied
+/// by the client and cannot be trusted.
+pub fn resolve(peer: SocketAddr, headers: &HeaderMap, trusted: &TrustedProxies) -> IpAddr {
+    let peer_ip = canonical(peer.ip());
+    if !trusted.contains(peer_ip) {
+        return peer_ip;
+    }
+
+    let hops = match trusted.header {
+        ProxyHeader::Forwarded => forwarded_hops(headers),
+        header => list_hops(headers, header.as_str()),
+    }
+    .unwrap_or_default();
+
+    let mut client = peer_ip;
+    for hop in hops.iter().rev() {
+        match hop {
+            Some(ip) if trusted.contains(*ip) => client = *ip,
+            Some(ip) => return *ip,
+            // An unknown or obfuscated hop breaks the chain; the last trusted
+            // address we saw is the best we can do.
+            None => return client,
+        }
+    }
+    client
+}
+
+fn forwarded_hops(headers: &HeaderMap) -> Option<Vec<Option<IpAddr>>> {
+    let mut hops = Vec::new();
+    for value in headers.get_all("forwarded") {
+        let value = value.to_str().ok()?;
+        for element in value.split(',') {
+            let node = element.split(';').find_map(|pair| {
+                let (name, value) = pair.trim().split_once('=')?;
+                name.eq_ignore_ascii_case("for").then_some(value)
+            });
+            hops.push(node.and_then(parse_node));
+        }
+    }
+    (!hops.is_empty()).then_some(hops)
+}
+
+// A comma-separated list of bare addresses, as in X-Forwarded-For
+fn list_hops(headers: &HeaderMap, name: &str) -> Option<Vec<Option<IpAddr>>> {
+    let mut hops = Vec::new();
+    for value in headers.get_all(name) {
+        let value = value.to_str().ok()?;
+        hops.extend(value.split(',').map(parse_node));
+    }
+    (!hops.is_empty()).then_some(hops)
+}
+
+/// Parses a node as it appears in `Forwarded` or `X-Forwarded-For`:
+/// `192.0.2.1`, `192.0.2.1:4711`, `"[2001:db8::1]:4711"`, `2001:db8::1`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    let ip = if let Ok(ip) = node.parse::<IpAddr>() {
+        ip
+    } else if let Ok(addr) = node.parse::<SocketAddr>() {
+        addr.ip()
+    } else {
+        node.strip_prefix('[')?.strip_suffix(']')?.parse().ok()?
+    };
+    Some(canonical(ip))
+}
+
+/// Groups IPv4 addresses by /24 and IPv6 addresses by /64. Anything that
+/// does not parse as an address is its own group.
+pub fn subnet_key(client_ip: &str) -> String {
+    match client_ip.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            let [a, b, c, _] = ip.octets();
+            format!("{}.{}.{}.0/24", a, b, c)
+        }
+        Ok(IpAddr::V6(ip)) => {
+            let s = ip.segments();
+            format!("{:x}:{:x}:{:x}:{:x}::/64", s[0], s[1], s[2], s[3])
+        }
+        Err(_) => client_ip.to_string(),
+    }
+}
+
+/// IPv4-mapped IPv6 addresses (`::ffff:192.0.2.1`) are treated as IPv4.
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        v4 => v4,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT: &str = "203.0.113.9";
+    const NGINX: &str = "127.0.0.1:50000";
+
+    fn proxies(entries: &[&str], header: ProxyHeader) -> TrustedProxies {
+        let entries: Vec<String> = entries.iter().map(|entry| entry.to_string()).collect();
+        TrustedProxies::parse(&entries, header).unwrap()
+    }
+
+    fn resolved(peer: &str, headers: &[(&'static str, &str)], trusted: &TrustedProxies) -> String {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.append(*name, value.parse().unwrap());
+        }
+        resolve(peer.parse().unwrap(), &map, trusted).to_string()
+    }
+
+    #[test]
+    fn untrusted_peers_are_the_client() {
+        let trusted = proxies(&["127.0.0.1"], ProxyHeader::XForwardedFor);
+        let headers = [
+            ("x-forwarded-for", "10.0.0.7"),
+            ("forwarded", "for=10.0.0.8"),
+            ("x-real-ip", "10.0.0.9"),
+        ];
+        assert_eq!(
+            resolved("198.51.100.4:443", &headers, &trusted),
+            "198.51.100.4"
+        );
+    }
+
+    #[test]
+    fn spoofed_forwarded_is_ignored() {
+        // nginx passes a client's own Forwarded header through untouched
+        let trusted = proxies(&["127.0.0.1"], ProxyHeader::XForwardedFor);
+        let headers = [
+            ("forwarded", "for=10.0.3.7"),
+            ("x-forwarded-for", CLIENT),
+            ("x-real-ip", "10.0.3.8"),
+        ];
+        assert_eq!(resolved(NGINX, &headers, &trusted), CLIENT);
+    }
+
+    #[test]
+    fn spoofed_x_forwarded_for_prefixes_are_ignored() {
+        // $proxy_add_x_forwarded_for appends the real peer to what the client
+        // sent
+        let trusted = proxies(&["127.0.0.1"], ProxyHeader::XForwardedFor);
+        let spoofed = format!("10.0.0.7, 127.0.0.1, {}", CLIENT);
+        assert_eq!(
+            resolved(NGINX, &[("x-forwarded-for", &spoofed)], &trusted),
+            CLIENT
+        );
+        // Split across several header lines the rightmost still wins
+        let headers = [("x-forwarded-for", "10.0.0.7"), ("x-forwarded-for", CLIENT)];
+        assert_eq!(resolved(NGINX, &headers, &trusted), CLIENT);
+    }
+
+    #[test]
+    fn chains_of_trusted_proxies_are_skipped() {
+        let trusted = proxies(&["127.0.0.1", "10.1.0.0/16"], ProxyHeader::XForwardedFor);
+        let chain = format!("10.0.0.7, {}, 10.1.2.3, 10.1.4.5", CLIENT);
+        assert_eq!(
+            resolved(NGINX, &[("x-forwarded-for", &chain)], &trusted),
+            CLIENT
+        );
+
+        // An unparseable hop stops the walk at the last trusted address
+        let broken = "10.0.0.7, unknown, 10.1.4.5";
+        assert_eq!(
+            resolved(NGINX, &[("x-forwarded-for", broken)], &trusted),
+            "10.1.4.5"
+        );
+
+        // Nothing but proxies: the one furthest from us
+        let proxies_only = "10.1.2.3, 10.1.4.5";
+        assert_eq!(
+            resolved(NGINX, &[("x-forwarded-for", proxies_only)], &trusted),
+            "10.1.2.3"
+        );
+    }
+
+    #[test]
+    fn only_the_configured_header_is_walked() {
+        let forwarded = proxies(&["127.0.0.1", "10.1.0.0/16"], ProxyHeader::Forwarded);
+        let headers = [
+            (
+                "forwarded",
+                "for=10.0.0.7, for=\"203.0.113.9:4711\";proto=https, for=10.1.4.5",
+            ),
+            ("x-forwarded-for", "10.0.0.8"),
+        ];
+        assert_eq!(resolved(NGINX, &headers, &forwarded), CLIENT);
+        assert_eq!(
+            resolved(NGINX, &[("x-forwarded-for", CLIENT)], &forwarded),
+            "127.0.0.1"
+        );
+
+        let real_ip = proxies(&["127.0.0.1"], ProxyHeader::XRealIp);
+        let headers = [("x-real-ip", CLIENT), ("x-forwarded-for", "10.0.0.8")];
+        assert_eq!(resolved(NGINX, &headers, &real_ip), CLIENT);
+    }
+
+    #[test]
+    fn missing_header_falls_back_to_the_peer() {
+        let trusted = proxies(&["127.0.0.1"], ProxyHeader::XForwardedFor);
+        assert_eq!(resolved(NGINX, &[], &trusted), "127.0.0.1");
+        assert_eq!(
+            resolved(NGINX, &[("x-real-ip", "10.0.0.9")], &trusted),
+            "127.0.0.1"
+        );
+    }
+}
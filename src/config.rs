@@ -0,0 +1,526 @@
+use clap::Parser;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use crate::binding::BindingConfig;
+use crate::challenge::Algorithm;
+use crate::client_ip::{ProxyHeader, TrustedProxies};
+use crate::difficulty::DifficultyConfig;
+use crate::logging::{LogFormat, LogOutput, SyslogConfig};
+use crate::rate_limit::RateLimitConfig;
+use crate::redact::RedactConfig;
+use crate::site::{default_sites, is_site_host, SiteConfig};
+
+/// The secret that shipped in the source tree. Anyone can read it, so the
+/// server refuses to run with it unless dev mode is explicitly enabled.
+pub const DEFAULT_SECRET_KEY: &str =
+    "ece5b7b9c637456c135dfe87f571bc5e757f5e4e51e24306c8917a69d8540206";
+
+const MIN_SECRET_LEN: usize = 32;
+
+/// Command line flags. These take precedence over environment variables,
+/// which in turn take precedence over the configuration file.
+#[derive(Parser, Debug, Clone)]
+#[command(
+    name = "altcha-server",
+    version,
+    about = "Self-hosted ALTCHA proof-of-work server"
+)]
+pub struct Cli {
+    /// Path to the TOML configuration file
+    #[arg(short, long, env = "ALTCHA_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Address to listen on (e.g. 127.0.0.1:8080)
+    #[arg(long)]
+    pub bind: Option<SocketAddr>,
+
+    /// Allow insecure development settings such as the published default secret
+    #[arg(long)]
+    pub dev: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// HMAC key used to sign challenges and verification cookies when no
+    /// `[[keys]]` are configured
+    pub secret_key: String,
+    /// Id of the key in `keys` that signs new challenges and cookies;
+    /// defaults to the first one
+    pub active_key: Option<String>,
+    /// Signing keys. All of them verify, only the active one signs.
+    pub keys: Vec<KeyConfig>,
+    pub server: ServerConfig,
+    pub challenge: ChallengeConfig,
+    pub cookie: CookieConfig,
+    pub difficulty: DifficultyConfig,
+    pub rate_limit: RateLimitConfig,
+    pub token: TokenConfig,
+    pub binding: BindingConfig,
+    pub proxy: ProxyConfig,
+    pub replay: ReplayConfig,
+    pub admin: AdminConfig,
+    pub logging: LoggingConfig,
+    /// The sites this server protects; requests for any other Host are
+    /// rejected
+    pub sites: Vec<SiteConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeyConfig {
+    pub id: String,
+    pub secret: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: SocketAddr,
+    pub dev_mode: bool,
+    /// How long in-flight requests may run after SIGTERM or SIGINT
+    pub shutdown_timeout_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChallengeConfig {
+    /// Base upper bound for the secret number a client has to find, before
+    /// the difficulty engine scales it; sites may override it
+    pub max_number: u32,
+    /// How long an issued challenge can be redeemed, in seconds
+    pub ttl_secs: u64,
+    /// Proof-of-work hash function for challenges; sites may override it
+    pub algorithm: Algorithm,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CookieConfig {
+    pub name: String,
+    /// Lifetime of the verification token and its cookie, in seconds
+    pub ttl_secs: u64,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TokenConfig {
+    /// Keep accepting legacy v0 tokens until this unix time; unset rejects them
+    pub accept_legacy_until: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxyConfig {
+    /// Addresses or CIDR ranges of reverse proxies whose `header` is
+    /// believed
+    pub trusted: Vec<String>,
+    /// The header those proxies set to the client address
+    pub header: ProxyHeader,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReplayConfig {
+    /// File the spent-challenge store is saved to and loaded from at
+    /// startup; unset keeps it in memory only
+    pub path: Option<PathBuf>,
+    /// How often new spends are saved to `path`, bounding what a crash loses
+    pub flush_interval_secs: u64,
+}
+
+impl Default for ReplayConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            flush_interval_secs: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// `text` or `json`; takes effect after a restart
+    pub format: LogFormat,
+    /// `stdout`, `file` or `syslog`; takes effect after a restart
+    pub output: LogOutput,
+    /// Log file for the `file` output, reopened on SIGUSR1
+    pub path: Option<PathBuf>,
+    /// Socket and facility for the `syslog` output
+    pub syslog: SyslogConfig,
+    /// How client IPs, tokens, salts and solutions appear in logs
+    pub redact: RedactConfig,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Bearer token for the /admin endpoints; they are disabled when unset
+    pub token: Option<String>,
+    /// Separate listener for /metrics and /admin; when unset they are served
+    /// on `server.bind`
+    pub bind: Option<SocketAddr>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            secret_key: DEFAULT_SECRET_KEY.to_string(),
+            active_key: None,
+            keys: Vec::new(),
+            server: ServerConfig::default(),
+            challenge: ChallengeConfig::default(),
+            cookie: CookieConfig::default(),
+            difficulty: DifficultyConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            token: TokenConfig::default(),
+            binding: BindingConfig::default(),
+            proxy: ProxyConfig::default(),
+            replay: ReplayConfig::default(),
+            admin: AdminConfig::default(),
+            logging: LoggingConfig::default(),
+            sites: default_sites(),
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: SocketAddr::from(([127, 0, 0, 1], 8080)),
+            dev_mode: false,
+            shutdown_timeout_secs: 10,
+        }
+    }
+}
+
+impl Default for ChallengeConfig {
+    fn default() -> Self {
+        Self {
+            max_number: 50000,
+            ttl_secs: 600,
+            algorithm: Algorithm::default(),
+        }
+    }
+}
+
+impl Default for CookieConfig {
+    fn default() -> Self {
+        Self {
+            name: "altcha_verified".to_string(),
+            ttl_secs: 86400, // 24 hours
+        }
+    }
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        Self {
+            trusted: vec!["127.0.0.1".to_string(), "::1".to_string()],
+            header: ProxyHeader::default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    Env {
+        var: &'static str,
+        message: String,
+    },
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io { path, source } => {
+                write!(f, "cannot read {}: {}", path.display(), source)
+            }
+            ConfigError::Parse { path, source } => {
+                write!(f, "invalid configuration in {}: {}", path.display(), source)
+            }
+            ConfigError::Env { var, message } => write!(f, "invalid value in {}: {}", var, message),
+            ConfigError::Invalid(message) => write!(f, "invalid configuration: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Io { source, .. } => Some(source),
+            ConfigError::Parse { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl Config {
+    /// Build the effective configuration: file, then environment, then CLI flags.
+    pub fn load(cli: &Cli) -> Result<Self, ConfigError> {
+        let mut config = match &cli.config {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+
+        config.apply_env(|var| std::env::var(var).ok())?;
+
+        if let Some(bind) = cli.bind {
+            config.server.bind = bind;
+        }
+        if cli.dev {
+            config.server.dev_mode = true;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let contents = std::fs::read_to_string(path).map_err(|source| ConfigError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        toml::from_str(&contents).map_err(|source| ConfigError::Parse {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+
+    fn apply_env(&mut self, get: impl Fn(&str) -> Option<String>) -> Result<(), ConfigError> {
+        if let Some(secret) = get("ALTCHA_SECRET_KEY") {
+            self.secret_key = secret;
+        }
+        if let Some(bind) = get("ALTCHA_BIND") {
+            self.server.bind = parse_env("ALTCHA_BIND", &bind)?;
+        }
+        if let Some(dev_mode) = get("ALTCHA_DEV_MODE") {
+            self.server.dev_mode = parse_env("ALTCHA_DEV_MODE", &dev_mode)?;
+        }
+        if let Some(max_number) = get("ALTCHA_MAX_NUMBER") {
+            self.challenge.max_number = parse_env("ALTCHA_MAX_NUMBER", &max_number)?;
+        }
+        if let Some(algorithm) = get("ALTCHA_ALGORITHM") {
+            self.challenge.algorithm = parse_env("ALTCHA_ALGORITHM", &algorithm)?;
+        }
+        if let Some(name) = get("ALTCHA_COOKIE_NAME") {
+            self.cookie.name = name;
+        }
+        if let Some(ttl) = get("ALTCHA_TOKEN_TTL") {
+            self.cookie.ttl_secs = parse_env("ALTCHA_TOKEN_TTL", &ttl)?;
+        }
+        if let Some(binding) = get("ALTCHA_BINDING") {
+            self.binding.default = parse_env("ALTCHA_BINDING", &binding)?;
+        }
+        if let Some(trusted) = get("ALTCHA_TRUSTED_PROXIES") {
+            self.proxy.trusted = trusted
+                .split(',')
+                .map(str::trim)
+                .filter(|entry| !entry.is_empty())
+                .map(str::to_string)
+                .collect();
+        }
+        if let Some(header) = get("ALTCHA_PROXY_HEADER") {
+            self.proxy.header = parse_env("ALTCHA_PROXY_HEADER", &header)?;
+        }
+        if let Some(token) = get("ALTCHA_ADMIN_TOKEN") {
+            self.admin.token = Some(token);
+        }
+        if let Some(bind) = get("ALTCHA_ADMIN_BIND") {
+            self.admin.bind = Some(parse_env("ALTCHA_ADMIN_BIND", &bind)?);
+        }
+        if let Some(timeout) = get("ALTCHA_SHUTDOWN_TIMEOUT") {
+            self.server.shutdown_timeout_secs = parse_env("ALTCHA_SHUTDOWN_TIMEOUT", &timeout)?;
+        }
+        if let Some(ttl) = get("ALTCHA_CHALLENGE_TTL") {
+            self.challenge.ttl_secs = parse_env("ALTCHA_CHALLENGE_TTL", &ttl)?;
+        }
+        if let Some(format) = get("ALTCHA_LOG_FORMAT") {
+            self.logging.format = parse_env("ALTCHA_LOG_FORMAT", &format)?;
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.keys.is_empty() {
+            self.validate_secret("secret_key", &self.secret_key)?;
+        } else {
+            self.validate_keys()?;
+        }
+        if self.challenge.max_number == 0 {
+            return Err(ConfigError::Invalid(
+                "challenge.max_number must be greater than 0".to_string(),
+            ));
+        }
+        self.difficulty
+            .validate(self.challenge.max_number)
+            .map_err(ConfigError::Invalid)?;
+        self.rate_limit.validate().map_err(ConfigError::Invalid)?;
+        if self.cookie.name.is_empty() || !self.cookie.name.bytes().all(is_cookie_name_byte) {
+            return Err(ConfigError::Invalid(format!(
+                "cookie.name {:?} is not a valid cookie name",
+                self.cookie.name
+            )));
+        }
+        if self.cookie.ttl_secs == 0 {
+            return Err(ConfigError::Invalid(
+                "cookie.ttl_secs must be greater than 0".to_string(),
+            ));
+        }
+        if self.challenge.ttl_secs == 0 {
+            return Err(ConfigError::Invalid(
+                "challenge.ttl_secs must be greater than 0".to_string(),
+            ));
+        }
+        if self.replay.flush_interval_secs == 0 {
+            return Err(ConfigError::Invalid(
+                "replay.flush_interval_secs must be greater than 0".to_string(),
+            ));
+        }
+        self.validate_sites()?;
+        TrustedProxies::parse(&self.proxy.trusted, self.proxy.header)
+            .map_err(|e| ConfigError::Invalid(format!("proxy.trusted: {}", e)))?;
+        if let Some(token) = &self.admin.token {
+            if token.len() < MIN_SECRET_LEN {
+                return Err(ConfigError::Invalid(format!(
+                    "admin.token must be at least {} characters",
+                    MIN_SECRET_LEN
+                )));
+            }
+        }
+        if self.admin.bind == Some(self.server.bind) {
+            return Err(ConfigError::Invalid(
+                "admin.bind must differ from server.bind".to_string(),
+            ));
+        }
+        if self.logging.output == LogOutput::File && self.logging.path.is_none() {
+            return Err(ConfigError::Invalid(
+                "logging.path is required when logging.output is \"file\"".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn validate_keys(&self) -> Result<(), ConfigError> {
+        let mut seen = HashSet::new();
+        for key in &self.keys {
+            if !is_key_id(&key.id) {
+                return Err(ConfigError::Invalid(format!(
+                    "key id {:?} must be 1-32 characters of A-Z, a-z, 0-9, '-' or '_'",
+                    key.id
+                )));
+            }
+            if !seen.insert(key.id.as_str()) {
+                return Err(ConfigError::Invalid(format!(
+                    "key id {:?} is configured more than once",
+                    key.id
+                )));
+            }
+            self.validate_secret(&format!("secret of key {:?}", key.id), &key.secret)?;
+        }
+        if let Some(active) = &self.active_key {
+            if !seen.contains(active.as_str()) {
+                return Err(ConfigError::Invalid(format!(
+                    "active_key {:?} does not match any configured key",
+                    active
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_sites(&self) -> Result<(), ConfigError> {
+        if self.sites.is_empty() {
+            return Err(ConfigError::Invalid(
+                "at least one [[sites]] entry is required".to_string(),
+            ));
+        }
+        let mut seen = HashSet::new();
+        for site in &self.sites {
+            let host = site.host.to_ascii_lowercase();
+            let invalid =
+                |what: &str| ConfigError::Invalid(format!("site {:?}: {}", site.host, what));
+            if !is_site_host(&host) {
+                return Err(invalid("host must be a hostname or *.domain"));
+            }
+            if !seen.insert(host) {
+                return Err(invalid("host is configured more than once"));
+            }
+            if let Some(max_number) = site.max_number {
+                if max_number == 0 || max_number > self.difficulty.cap {
+                    return Err(invalid("max_number must be between 1 and difficulty.cap"));
+                }
+            }
+            if site.token_ttl_secs == Some(0) {
+                return Err(invalid("token_ttl_secs must be greater than 0"));
+            }
+            if let Some(bad) = site.return_hosts.iter().find(|host| !is_site_host(host)) {
+                return Err(invalid(&format!(
+                    "return_hosts entry {:?} must be a hostname or *.domain",
+                    bad
+                )));
+            }
+            if let Some(name) = &site.cookie_name {
+                if name.is_empty() || !name.bytes().all(is_cookie_name_byte) {
+                    return Err(invalid("cookie_name is not a valid cookie name"));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_secret(&self, name: &str, secret: &str) -> Result<(), ConfigError> {
+        if secret == DEFAULT_SECRET_KEY && !self.server.dev_mode {
+            return Err(ConfigError::Invalid(format!(
+                "{} is the published default; set a private key (openssl rand -hex 32) \
+                 or start with --dev for local development",
+                name
+            )));
+        }
+        if secret.len() < MIN_SECRET_LEN {
+            return Err(ConfigError::Invalid(format!(
+                "{} must be at least {} characters",
+                name, MIN_SECRET_LEN
+            )));
+        }
+        Ok(())
+    }
+}
+
+fn parse_env<T>(var: &'static str, value: &str) -> Result<T, ConfigError>
+where
+    T: std::str::FromStr,
+    T::Err: fmt::Display,
+{
+    value.trim().parse().map_err(|e: T::Err| ConfigError::Env {
+        var,
+        message: e.to_string(),
+    })
+}
+
+// Key ids end up inside cookie values and dot-separated tokens
+fn is_key_id(kid: &str) -> bool {
+    (1..=32).contains(&kid.len())
+        && kid
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+// RFC 6265 cookie-name is an RFC 7230 token
+fn is_cookie_name_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
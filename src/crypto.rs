@@ -0,0 +1,227 @@
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fmt;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Length of a hex-encoded HMAC-SHA256 tag.
+const SIGNATURE_HEX_LEN: usize = 64;
+
+/// Fixed HKDF salt; the master secret carries all of the entropy.
+const HKDF_SALT: &[u8] = b"altcha-server";
+
+/// What a derived key is allowed to sign. Each purpose gets its own key so a
+/// signature made for one kind of data can never be passed off as another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Purpose {
+    Challenge,
+    Token,
+    ReturnTo,
+    /// Keyed hashes that pseudonymize values in logs
+    LogPseudonym,
+}
+
+impl Purpose {
+    fn label(self) -> &'static [u8] {
+        match self {
+            Purpose::Challenge => b"altcha-server/challenge/v1",
+            Purpose::Token => b"altcha-server/token/v1",
+            Purpose::ReturnTo => b"altcha-server/return-to/v1",
+            Purpose::LogPseudonym => b"altcha-server/log-pseudonym/v1",
+        }
+    }
+}
+
+/// HKDF-SHA256(master, label) -> 32-byte subkey for `purpose`.
+pub fn derive_key(master: &[u8], purpose: Purpose) -> [u8; 32] {
+    let mut okm = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(HKDF_SALT), master)
+        .expand(purpose.label(), &mut okm)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    okm
+}
+
+/// Hex-encoded HMAC-SHA256 of `data` under `key`.
+pub fn hmac_hex(key: &[u8], data: &[u8]) -> String {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MacError {
+    /// Not exactly one HMAC-SHA256 tag long
+    WrongLength,
+    /// Right length but not hex
+    Malformed,
+    /// Well-formed but does not match
+    Mismatch,
+}
+
+impl fmt::Display for MacError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MacError::WrongLength => write!(f, "signature has the wrong length"),
+            MacError::Malformed => write!(f, "signature is not valid hex"),
+            MacError::Mismatch => write!(f, "signature does not match"),
+        }
+    }
+}
+
+impl std::error::Error for MacError {}
+
+/// Checks a hex-encoded HMAC-SHA256 `signature` over `data` in constant time.
+///
+/// Every signature check goes through here: the shape of the input is
+/// validated first, then the `hmac` crate compares the raw tags.
+pub fn verify_hmac_hex(key: &[u8], data: &[u8], signature: &str) -> Result<(), MacError> {
+    if signature.len() != SIGNATURE_HEX_LEN {
+        return Err(MacError::WrongLength);
+    }
+    let tag = hex::decode(signature).map_err(|_| MacError::Malformed)?;
+
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.verify_slice(&tag).map_err(|_| MacError::Mismatch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::challenge::{verify_solution, Algorithm, AltchaPayload, SolutionError};
+    use crate::test_support::{key, solved, SECRET};
+    use crate::token::{Claims, TokenCodec, TokenError};
+
+    const DATA: &[u8] = b"ip|domain|expires";
+
+    // Vectors computed independently per RFC 5869 (HKDF-SHA256, salt
+    // "altcha-server", L = 32) so a change to the derivation is caught.
+    #[test]
+    fn derived_keys_match_test_vectors() {
+        assert_eq!(
+            hex::encode(derive_key(SECRET.as_bytes(), Purpose::Challenge)),
+            "b6d2ef65648363c31c4261be5163f6680a77c0a7d8dc0c93f6c60b02c735791e"
+        );
+        assert_eq!(
+            hex::encode(derive_key(SECRET.as_bytes(), Purpose::Token)),
+            "0aba9c457f6d4e7bae25638d4bdd9064eea65efa524870fea58c80989e689711"
+        );
+        assert_eq!(
+            hex::encode(derive_key(SECRET.as_bytes(), Purpose::ReturnTo)),
+            "4e9450f3c064ee99a5f8ae40983d5f6020b42f3eb3b798ade5836f80633d05c4"
+        );
+    }
+
+    #[test]
+    fn purposes_derive_distinct_keys() {
+        let key = key();
+        assert_ne!(key.challenge_key(), key.token_key());
+        assert_ne!(key.return_to_key(), key.challenge_key());
+        assert_ne!(key.return_to_key(), key.token_key());
+        assert_ne!(key.pseudonym_key(), key.challenge_key());
+        assert_ne!(key.pseudonym_key(), key.token_key());
+        assert_ne!(key.challenge_key(), SECRET.as_bytes());
+        assert_ne!(key.token_key(), SECRET.as_bytes());
+    }
+
+    #[test]
+    fn challenge_signature_never_validates_as_token() {
+        let key = key();
+        let codec = TokenCodec::new(None);
+        let claims = Claims {
+            binding: Default::default(),
+            subject: "192.0.2.1".to_string(),
+            domain: "example.org".to_string(),
+            iat: 1,
+            exp: u64::MAX,
+            ext: Default::default(),
+        };
+        let token = codec.issue(&claims, &key).unwrap();
+        assert_eq!(
+            codec.open(&token, std::slice::from_ref(&key)).unwrap(),
+            claims
+        );
+
+        // Re-sign the same token body with the challenge key
+        let (signed, _) = token.rsplit_once('.').unwrap();
+        let forged = format!(
+            "{}.{}",
+            signed,
+            hmac_hex(key.challenge_key(), signed.as_bytes())
+        );
+        assert!(matches!(
+            codec.open(&forged, &[key]),
+            Err(TokenError::BadSignature(MacError::Mismatch))
+        ));
+    }
+
+    #[test]
+    fn token_signature_never_validates_as_challenge() {
+        let key = key();
+        let algorithm = Algorithm::Sha256;
+        let solved = solved(
+            "00112233445566778899aabbccddeeff?expires=18446744073709551615&maxnumber=10",
+            7,
+        );
+        assert_eq!(verify_solution(&solved, std::slice::from_ref(&key)), Ok(()));
+
+        // The same signed data under the token key must not pass
+        let signed = format!("{}{}{}", algorithm, solved.challenge, solved.salt);
+        let forged = AltchaPayload {
+            signature: hmac_hex(key.token_key(), signed.as_bytes()),
+            ..solved
+        };
+        assert_eq!(
+            verify_solution(&forged, &[key]),
+            Err(SolutionError::InvalidSignature(MacError::Mismatch))
+        );
+    }
+
+    #[test]
+    fn verify_accepts_matching_signature() {
+        let signature = hmac_hex(SECRET.as_bytes(), DATA);
+        assert_eq!(verify_hmac_hex(SECRET.as_bytes(), DATA, &signature), Ok(()));
+    }
+
+    #[test]
+    fn verify_rejects_mismatched_signature() {
+        let signature = hmac_hex(b"some other key", DATA);
+        assert_eq!(
+            verify_hmac_hex(SECRET.as_bytes(), DATA, &signature),
+            Err(MacError::Mismatch)
+        );
+    }
+
+    #[test]
+    fn verify_rejects_wrong_length_signature() {
+        let signature = hmac_hex(SECRET.as_bytes(), DATA);
+        for wrong in [
+            &signature[..62],
+            &signature[..32],
+            "",
+            &format!("{}00", signature),
+        ] {
+            assert_eq!(
+                verify_hmac_hex(SECRET.as_bytes(), DATA, wrong),
+                Err(MacError::WrongLength)
+            );
+        }
+    }
+
+    #[test]
+    fn verify_rejects_non_hex_signature() {
+        let signature = hmac_hex(SECRET.as_bytes(), DATA);
+        let non_hex = format!("{}zz", &signature[..62]);
+        assert_eq!(
+            verify_hmac_hex(SECRET.as_bytes(), DATA, &non_hex),
+            Err(MacError::Malformed)
+        );
+        // Multi-byte characters must not slip past the length check
+        let multibyte = format!("{}é", &signature[..62]);
+        assert_eq!(
+            verify_hmac_hex(SECRET.as_bytes(), DATA, &multibyte),
+            Err(MacError::Malformed)
+        );
+    }
+}
@@ -0,0 +1,265 @@
+use lru::LruCache;
+use serde::Deserialize;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+use tracing::warn;
+
+use crate::client_ip::subnet_key;
+
+/// A multiplier that applies once the observed request rate reaches `requests`
+/// per window.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Tier {
+    pub requests: u32,
+    pub multiplier: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DifficultyConfig {
+    /// Length of the rate window, in seconds
+    pub window_secs: u64,
+    /// Hard upper bound for `maxnumber`, whatever the tiers say
+    pub cap: u32,
+    /// Tiers keyed on a single client IP's challenge/verify rate
+    pub ip_tiers: Vec<Tier>,
+    /// Tiers keyed on the client's subnet (IPv4 /24, IPv6 /64)
+    pub subnet_tiers: Vec<Tier>,
+    /// Tiers keyed on the rate across all clients
+    pub global_tiers: Vec<Tier>,
+    /// Maximum number of IPs and subnets tracked at once; the least recently
+    /// seen are dropped first
+    pub max_tracked: usize,
+}
+
+impl Default for DifficultyConfig {
+    fn default() -> Self {
+        Self {
+            window_secs: 60,
+            cap: 1_000_000,
+            ip_tiers: vec![
+                Tier {
+                    requests: 10,
+                    multiplier: 2,
+                },
+                Tier {
+                    requests: 30,
+                    multiplier: 5,
+                },
+                Tier {
+                    requests: 100,
+                    multiplier: 20,
+                },
+            ],
+            subnet_tiers: vec![
+                Tier {
+                    requests: 100,
+                    multiplier: 2,
+                },
+                Tier {
+                    requests: 500,
+                    multiplier: 5,
+                },
+            ],
+            global_tiers: vec![Tier {
+                requests: 5000,
+                multiplier: 2,
+            }],
+            max_tracked: 100_000,
+        }
+    }
+}
+
+impl DifficultyConfig {
+    pub fn validate(&self, base: u32) -> Result<(), String> {
+        if self.window_secs == 0 {
+            return Err("difficulty.window_secs must be greater than 0".to_string());
+        }
+        if self.cap < base {
+            return Err(format!(
+                "difficulty.cap ({}) must not be below challenge.max_number ({})",
+                self.cap, base
+            ));
+        }
+        let tiers = [
+            ("ip_tiers", &self.ip_tiers),
+            ("subnet_tiers", &self.subnet_tiers),
+            ("global_tiers", &self.global_tiers),
+        ];
+        for (name, tiers) in tiers {
+            if tiers.iter().any(|tier| tier.multiplier == 0) {
+                return Err(format!(
+                    "difficulty.{} multipliers must be at least 1",
+                    name
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Approximate sliding-window counter: the previous window's count is
+/// weighted by how much of it still overlaps the sliding window.
+#[derive(Debug, Clone, Copy)]
+struct RateCounter {
+    window_start: Instant,
+    current: u32,
+    previous: u32,
+}
+
+impl RateCounter {
+    fn new(now: Instant) -> Self {
+        Self {
+            window_start: now,
+            current: 0,
+            previous: 0,
+        }
+    }
+
+    fn roll(&mut self, now: Instant, window_secs: u64) {
+        let elapsed = now.duration_since(self.window_start).as_secs();
+        if elapsed >= 2 * window_secs {
+            self.previous = 0;
+            self.current = 0;
+            self.window_start = now;
+        } else if elapsed >= window_secs {
+            self.previous = self.current;
+            self.current = 0;
+            self.window_start += std::time::Duration::from_secs(window_secs);
+        }
+    }
+
+    fn rate(&self, now: Instant, window_secs: u64) -> u32 {
+        let window = window_secs as f64;
+        let elapsed = now
+            .duration_since(self.window_start)
+            .as_secs_f64()
+            .min(window);
+        let overlap = (window - elapsed) / window;
+        self.current + (self.previous as f64 * overlap) as u32
+    }
+}
+
+struct Counters {
+    ips: LruCache<String, RateCounter>,
+    subnets: LruCache<String, RateCounter>,
+    global: RateCounter,
+    /// Whether running out of room has been logged, so a flood of new
+    /// addresses cannot flood the log too
+    full_logged: bool,
+}
+
+/// Scales the challenge `maxnumber` with the requester's recent activity.
+pub struct DifficultyEngine {
+    config: RwLock<Arc<DifficultyConfig>>,
+    counters: Mutex<Counters>,
+}
+
+impl DifficultyEngine {
+    pub fn new(config: DifficultyConfig) -> Self {
+        let capacity = capacity(&config);
+        Self {
+            config: RwLock::new(Arc::new(config)),
+            counters: Mutex::new(Counters {
+                ips: LruCache::new(capacity),
+                subnets: LruCache::new(capacity),
+                global: RateCounter::new(Instant::now()),
+                full_logged: false,
+            }),
+        }
+    }
+
+    /// Swaps in new tiers and limits. Request counts carry over.
+    pub fn reconfigure(&self, config: DifficultyConfig) {
+        let capacity = capacity(&config);
+        let mut counters = self.counters.lock().unwrap_or_else(|e| e.into_inner());
+        counters.ips.resize(capacity);
+        counters.subnets.resize(capacity);
+        counters.full_logged = false;
+        drop(counters);
+        *self.config.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(config);
+    }
+
+    fn config(&self) -> Arc<DifficultyConfig> {
+        self.config
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Counts one challenge or verify request from `client_ip`.
+    pub fn record(&self, client_ip: &str) {
+        let now = Instant::now();
+        let config = self.config();
+        let window = config.window_secs;
+        let mut guard = self.counters.lock().unwrap_or_else(|e| e.into_inner());
+        let counters = &mut *guard;
+
+        counters.global.roll(now, window);
+        counters.global.current = counters.global.current.saturating_add(1);
+
+        let subnet = subnet_key(client_ip);
+        for (map, key) in [
+            (&mut counters.ips, client_ip.to_string()),
+            (&mut counters.subnets, subnet),
+        ] {
+            if map.len() == map.cap().get() && !map.contains(&key) && !counters.full_logged {
+                // Global pressure keeps raising difficulty for everyone while
+                // the least recently seen clients are forgotten
+                warn!(
+                    entries = map.len(),
+                    "Difficulty tracker full, dropping least recently seen clients"
+                );
+                counters.full_logged = true;
+            }
+            let counter = map.get_or_insert_mut(key, || RateCounter::new(now));
+            counter.roll(now, window);
+            counter.current = counter.current.saturating_add(1);
+        }
+    }
+
+    /// The `maxnumber` to issue to `client_ip`, scaled up from the site's
+    /// `base` difficulty.
+    pub fn max_number_for(&self, client_ip: &str, base: u32) -> u32 {
+        let now = Instant::now();
+        let config = self.config();
+        let window = config.window_secs;
+        let counters = self.counters.lock().unwrap_or_else(|e| e.into_inner());
+
+        let rate_of = |map: &LruCache<String, RateCounter>, key: &str| {
+            map.peek(key).map_or(0, |counter| {
+                let mut counter = *counter;
+                counter.roll(now, window);
+                counter.rate(now, window)
+            })
+        };
+
+        let ip_rate = rate_of(&counters.ips, client_ip);
+        let subnet_rate = rate_of(&counters.subnets, &subnet_key(client_ip));
+        let mut global = counters.global;
+        global.roll(now, window);
+        let global_rate = global.rate(now, window);
+
+        let client_multiplier = multiplier(&config.ip_tiers, ip_rate)
+            .max(multiplier(&config.subnet_tiers, subnet_rate));
+        let global_multiplier = multiplier(&config.global_tiers, global_rate);
+
+        let scaled = u64::from(base) * u64::from(client_multiplier) * u64::from(global_multiplier);
+        scaled.min(u64::from(config.cap)) as u32
+    }
+}
+
+fn capacity(config: &DifficultyConfig) -> NonZeroUsize {
+    NonZeroUsize::new(config.max_tracked).unwrap_or(NonZeroUsize::MIN)
+}
+
+fn multiplier(tiers: &[Tier], rate: u32) -> u32 {
+    tiers
+        .iter()
+        .filter(|tier| rate >= tier.requests)
+        .map(|tier| tier.multiplier)
+        .max()
+        .unwrap_or(1)
+}
@@ -0,0 +1,151 @@
+use axum::{
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use std::fmt;
+
+use crate::challenge::{PayloadError, SolutionError};
+use crate::token::TokenError;
+
+/// Everything a handler can fail with. Each error answers with its HTTP
+/// status and a JSON body:
+///
+/// ```json
+/// {"code": "pow_mismatch", "message": "proof of work does not match the challenge"}
+/// ```
+///
+/// `code` is stable and meant for clients and monitoring; `message` is for
+/// humans and may change.
+#[derive(Debug)]
+pub enum AppError {
+    /// The Host is not a configured site; the status depends on the endpoint
+    UnknownHost(StatusCode),
+    /// The Host header is not a hostname with an optional port
+    InvalidHost,
+    /// The request body could not be read as a verification request
+    InvalidRequest(String),
+    UnsupportedMediaType,
+    /// The `altcha` field was rejected before its solution was checked
+    Payload(PayloadError),
+    /// The solution was checked and rejected
+    Solution(SolutionError),
+    /// The solution has already been redeemed
+    Replayed,
+    /// No verification cookie was sent
+    MissingToken,
+    /// The verification cookie was checked and rejected
+    Token(TokenError),
+    /// Wrong or missing admin bearer token
+    Unauthorized,
+    NotFound,
+    /// Too many requests; retry after this many seconds
+    RateLimited(u64),
+    /// Details are logged where the error occurs, never sent to the client
+    Internal,
+}
+
+impl AppError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::UnknownHost(status) => *status,
+            AppError::InvalidHost
+            | AppError::InvalidRequest(_)
+            | AppError::Payload(_)
+            | AppError::Solution(_)
+            | AppError::Replayed => StatusCode::BAD_REQUEST,
+            AppError::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            AppError::MissingToken | AppError::Token(_) | AppError::Unauthorized => {
+                StatusCode::UNAUTHORIZED
+            }
+            AppError::NotFound => StatusCode::NOT_FOUND,
+            AppError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            AppError::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Machine-readable reason, stable across releases.
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::UnknownHost(_) => "unknown_host",
+            AppError::InvalidHost => "invalid_host",
+            AppError::InvalidRequest(_) => "invalid_request",
+            AppError::UnsupportedMediaType => "unsupported_media_type",
+            AppError::Payload(e) => e.code(),
+            AppError::Solution(e) => e.code(),
+            AppError::Replayed => "replayed",
+            AppError::MissingToken => "missing_token",
+            AppError::Token(e) => e.code(),
+            AppError::Unauthorized => "unauthorized",
+            AppError::NotFound => "not_found",
+            AppError::RateLimited(_) => "rate_limited",
+            AppError::Internal => "internal",
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::UnknownHost(_) => write!(f, "this host is not served here"),
+            AppError::InvalidHost => write!(f, "invalid Host header"),
+            AppError::InvalidRequest(reason) => write!(f, "invalid request: {}", reason),
+            AppError::UnsupportedMediaType => {
+                write!(f, "expected application/json or a form post")
+            }
+            AppError::Payload(e) => e.fmt(f),
+            AppError::Solution(e) => e.fmt(f),
+            AppError::Replayed => write!(f, "this solution has already been used"),
+            AppError::MissingToken => write!(f, "no verification cookie"),
+            AppError::Token(e) => e.fmt(f),
+            AppError::Unauthorized => write!(f, "missing or wrong admin token"),
+            AppError::NotFound => write!(f, "not found"),
+            AppError::RateLimited(secs) => {
+                write!(f, "too many requests, retry in {} seconds", secs)
+            }
+            AppError::Internal => write!(f, "internal server error"),
+        }
+    }
+}
+
+impl std::error::Error for AppError {}
+
+impl From<PayloadError> for AppError {
+    fn from(e: PayloadError) -> Self {
+        AppError::Payload(e)
+    }
+}
+
+impl From<SolutionError> for AppError {
+    fn from(e: SolutionError) -> Self {
+        AppError::Solution(e)
+    }
+}
+
+impl From<TokenError> for AppError {
+    fn from(e: TokenError) -> Self {
+        AppError::Token(e)
+    }
+}
+
+/// The [`AppError::code`] a response failed with, left in its extensions
+/// for the request log.
+#[derive(Debug, Clone, Copy)]
+pub struct ErrorCode(pub &'static str);
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let body = Json(serde_json::json!({
+            "code": self.code(),
+            "message": self.to_string(),
+        }));
+        let mut response = (self.status(), body).into_response();
+        response.extensions_mut().insert(ErrorCode(self.code()));
+        if let AppError::RateLimited(secs) = self {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, secs.into());
+        }
+        response
+    }
+}
@@ -0,0 +1,202 @@
+use std::fmt;
+use std::sync::{Arc, RwLock};
+
+use crate::config::Config;
+use crate::crypto::{derive_key, Purpose};
+
+/// A master secret, the id it is known by in tokens and logs, and the
+/// per-purpose subkeys derived from it.
+#[derive(Clone)]
+pub struct Key {
+    pub id: String,
+    /// Raw master secret; only legacy v0 tokens are signed with it directly
+    pub secret: String,
+    challenge_key: [u8; 32],
+    token_key: [u8; 32],
+    return_to_key: [u8; 32],
+    pseudonym_key: [u8; 32],
+}
+
+impl Key {
+    pub fn new(id: &str, secret: &str) -> Self {
+        Self {
+            id: id.to_string(),
+            secret: secret.to_string(),
+            challenge_key: derive_key(secret.as_bytes(), Purpose::Challenge),
+            token_key: derive_key(secret.as_bytes(), Purpose::Token),
+            return_to_key: derive_key(secret.as_bytes(), Purpose::ReturnTo),
+            pseudonym_key: derive_key(secret.as_bytes(), Purpose::LogPseudonym),
+        }
+    }
+
+    /// Signs and verifies challenges.
+    pub fn challenge_key(&self) -> &[u8] {
+        &self.challenge_key
+    }
+
+    /// Signs and verifies verification tokens.
+    pub fn token_key(&self) -> &[u8] {
+        &self.token_key
+    }
+
+    /// Signs and verifies the return_to URL carried through verification.
+    pub fn return_to_key(&self) -> &[u8] {
+        &self.return_to_key
+    }
+
+    /// Keys the pseudonyms that stand in for client IPs and other values in
+    /// logs. Only the active key's is used, so pseudonyms change when the
+    /// active key is rotated and log lines from either side of a rotation
+    /// cannot be linked.
+    pub fn pseudonym_key(&self) -> &[u8] {
+        &self.pseudonym_key
+    }
+}
+
+// Keep secrets out of debug output and logs
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Key")
+            .field("id", &self.id)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The active signing key plus any previous keys that are still accepted
+/// for verification while their challenges and cookies age out.
+#[derive(Debug)]
+pub struct Keyring {
+    /// Active key first, then verify-only keys in configuration order
+    keys: Vec<Key>,
+}
+
+impl Keyring {
+    /// Builds the keyring from an already validated configuration.
+    pub fn from_config(config: &Config) -> Self {
+        if config.keys.is_empty() {
+            return Self {
+                keys: vec![Key::new("default", &config.secret_key)],
+            };
+        }
+
+        let active_id = config.active_key.as_deref().unwrap_or(&config.keys[0].id);
+        let mut keys: Vec<Key> = config
+            .keys
+            .iter()
+            .map(|key| Key::new(&key.id, &key.secret))
+            .collect();
+        if let Some(pos) = keys.iter().position(|key| key.id == active_id) {
+            keys[..=pos].rotate_right(1);
+        }
+        Self { keys }
+    }
+
+    /// The key new challenges and tokens are signed with.
+    pub fn active(&self) -> &Key {
+        &self.keys[0]
+    }
+
+    /// Every key that may verify a signature, active key first.
+    pub fn keys(&self) -> &[Key] {
+        &self.keys
+    }
+
+    pub fn ids(&self) -> Vec<&str> {
+        self.keys.iter().map(|key| key.id.as_str()).collect()
+    }
+}
+
+/// Shared handle that lets the keyring be swapped at runtime. Readers take a
+/// cheap snapshot so a reload never blocks or tears an in-flight request.
+pub struct SharedKeyring {
+    current: RwLock<Arc<Keyring>>,
+}
+
+impl SharedKeyring {
+    pub fn new(keyring: Keyring) -> Self {
+        Self {
+            current: RwLock::new(Arc::new(keyring)),
+        }
+    }
+
+    pub fn load(&self) -> Arc<Keyring> {
+        self.current
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    pub fn replace(&self, keyring: Keyring) {
+        *self.current.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(keyring);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::binding::BindingPolicy;
+    use crate::config::KeyConfig;
+    use crate::token::{create_verification_token, verify_token, TokenCodec};
+
+    const CLIENT_IP: &str = "192.0.2.10";
+    const DOMAIN: &str = "dataspace.princeton.edu";
+
+    fn keyring(keys: &[&str], active: &str) -> Keyring {
+        Keyring::from_config(&Config {
+            active_key: Some(active.to_string()),
+            keys: keys
+                .iter()
+                .map(|id| KeyConfig {
+                    id: id.to_string(),
+                    secret: format!("{}-000102030405060708090a0b0c0d0e0f", id),
+                })
+                .collect(),
+            ..Config::default()
+        })
+    }
+
+    fn issue(keyring: &Keyring) -> String {
+        create_verification_token(
+            &TokenCodec::new(None),
+            BindingPolicy::Exact,
+            CLIENT_IP,
+            "",
+            DOMAIN,
+            keyring.active(),
+            60,
+        )
+        .unwrap()
+    }
+
+    fn verify(keyring: &Keyring, token: &str) -> Result<(), &'static str> {
+        verify_token(
+            &TokenCodec::new(None),
+            token,
+            CLIENT_IP,
+            "",
+            DOMAIN,
+            keyring.keys(),
+        )
+        .map(|_| ())
+        .map_err(|e| e.code())
+    }
+
+    #[test]
+    fn the_active_key_signs() {
+        let keyring = keyring(&["2025", "2026"], "2026");
+        assert_eq!(keyring.active().id, "2026");
+        assert_eq!(keyring.ids(), ["2026", "2025"]);
+        assert!(issue(&keyring).starts_with("v1.2026."));
+    }
+
+    #[test]
+    fn previous_keys_verify_until_they_are_removed() {
+        let token = issue(&keyring(&["2025", "2026"], "2025"));
+
+        let rotated = keyring(&["2025", "2026"], "2026");
+        assert_eq!(verify(&rotated, &token), Ok(()));
+
+        let removed = keyring(&["2026"], "2026");
+        assert_eq!(verify(&removed, &token), Err("unknown_key"));
+    }
+}
@@ -0,0 +1,32 @@
+//! Library side of the ALTCHA server: everything but the HTTP handlers,
+//! which live in the binary. Split out so fuzz targets can link the parsers.
+
+pub mod assets;
+pub mod binding;
+pub mod challenge;
+pub mod client_ip;
+pub mod config;
+pub mod crypto;
+pub mod difficulty;
+pub mod error;
+pub mod keyring;
+pub mod logging;
+pub mod metrics;
+pub mod page;
+pub mod rate_limit;
+pub mod redact;
+pub mod replay;
+pub mod return_to;
+pub mod site;
+#[cfg(test)]
+mod test_support;
+pub mod token;
+
+pub fn unix_now() -> u64 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
@@ -0,0 +1,373 @@
+use axum::http::{HeaderMap, HeaderName};
+use rand::Rng;
+use serde::Deserialize;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use tracing::{Level, Metadata};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::writer::{BoxMakeWriter, MakeWriter};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{Layer, Registry};
+
+use crate::config::{ConfigError, LoggingConfig};
+
+/// Correlates the log lines of one request; taken from nginx when it sends
+/// one (`proxy_set_header X-Request-ID $request_id;`) and echoed back.
+pub static REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Longest incoming request ID that is kept; nginx's `$request_id` is 32.
+const MAX_REQUEST_ID_LEN: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, event and span fields at the top level
+    Json,
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LogFormat::Text => "text",
+            LogFormat::Json => "json",
+        })
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            other => Err(format!("unknown log format {:?}", other)),
+        }
+    }
+}
+
+/// Where log lines go.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogOutput {
+    #[default]
+    Stdout,
+    /// Appended to `logging.path`, reopened on SIGUSR1
+    File,
+    /// To the local syslog daemon, see [`SyslogConfig`]
+    Syslog,
+}
+
+/// Syslog facilities a daemon would log under.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Facility {
+    User,
+    #[default]
+    Daemon,
+    Auth,
+    Local0,
+    Local1,
+    Local2,
+    Local3,
+    Local4,
+    Local5,
+    Local6,
+    Local7,
+}
+
+impl Facility {
+    fn code(self) -> u8 {
+        match self {
+            Facility::User => 1,
+            Facility::Daemon => 3,
+            Facility::Auth => 4,
+            Facility::Local0 => 16,
+            Facility::Local1 => 17,
+            Facility::Local2 => 18,
+            Facility::Local3 => 19,
+            Facility::Local4 => 20,
+            Facility::Local5 => 21,
+            Facility::Local6 => 22,
+            Facility::Local7 => 23,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SyslogConfig {
+    /// The syslog daemon's Unix datagram socket
+    pub socket: PathBuf,
+    pub facility: Facility,
+}
+
+impl Default for SyslogConfig {
+    fn default() -> Self {
+        Self {
+            socket: PathBuf::from("/dev/log"),
+            facility: Facility::default(),
+        }
+    }
+}
+
+/// The output the subscriber writes to, kept so it can be reopened.
+pub enum Output {
+    Stdout,
+    File(LogFile),
+    Syslog(Syslog),
+}
+
+impl Output {
+    /// Reopens the log file, or reconnects to syslog, e.g. after rotation.
+    pub fn reopen(&self) -> io::Result<()> {
+        match self {
+            Output::Stdout => Ok(()),
+            Output::File(file) => file.reopen(),
+            Output::Syslog(syslog) => syslog.reconnect(),
+        }
+    }
+}
+
+/// Installs the global `tracing` subscriber. Call once, at startup.
+pub fn init(config: &LoggingConfig) -> Result<Output, ConfigError> {
+    let (output, writer) = match config.output {
+        LogOutput::Stdout => (Output::Stdout, BoxMakeWriter::new(io::stdout)),
+        LogOutput::File => {
+            let path = config.path.as_deref().ok_or_else(|| {
+                ConfigError::Invalid("logging.path is required for file output".to_string())
+            })?;
+            let file = LogFile::open(path).map_err(|e| {
+                ConfigError::Invalid(format!("logging.path {}: {}", path.display(), e))
+            })?;
+            (Output::File(file.clone()), BoxMakeWriter::new(file))
+        }
+        LogOutput::Syslog => {
+            let syslog = Syslog::connect(&config.syslog).map_err(|e| {
+                ConfigError::Invalid(format!(
+                    "logging.syslog.socket {}: {}",
+                    config.syslog.socket.display(),
+                    e
+                ))
+            })?;
+            (Output::Syslog(syslog.clone()), BoxMakeWriter::new(syslog))
+        }
+    };
+    // Colours only for a terminal; syslog stamps messages itself
+    let ansi = config.output == LogOutput::Stdout;
+    let timestamps = config.output != LogOutput::Syslog;
+
+    let layer: Box<dyn Layer<Registry> + Send + Sync> = match (config.format, timestamps) {
+        (LogFormat::Text, true) => tracing_subscriber::fmt::layer()
+            .with_ansi(ansi)
+            .with_writer(writer)
+            .boxed(),
+        (LogFormat::Text, false) => tracing_subscriber::fmt::layer()
+            .with_ansi(ansi)
+            .without_time()
+            .with_writer(writer)
+            .boxed(),
+        (LogFormat::Json, true) => tracing_subscriber::fmt::layer()
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(false)
+            .with_writer(writer)
+            .boxed(),
+        (LogFormat::Json, false) => tracing_subscriber::fmt::layer()
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(false)
+            .without_time()
+            .with_writer(writer)
+            .boxed(),
+    };
+    tracing_subscriber::registry()
+        .with(layer.with_filter(LevelFilter::INFO))
+        .init();
+    Ok(output)
+}
+
+/// A log file opened for appending. [`LogFile::reopen`] switches to a fresh
+/// file at the same path once newsyslog or logrotate has moved the old one.
+#[derive(Clone)]
+pub struct LogFile {
+    path: PathBuf,
+    file: Arc<Mutex<File>>,
+}
+
+impl LogFile {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: Arc::new(Mutex::new(open_append(path)?)),
+        })
+    }
+
+    pub fn reopen(&self) -> io::Result<()> {
+        let file = open_append(&self.path)?;
+        *self.file.lock().unwrap_or_else(|e| e.into_inner()) = file;
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o640)
+        .open(path)
+}
+
+impl<'a> MakeWriter<'a> for LogFile {
+    type Writer = LogFileWriter<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        LogFileWriter(self.file.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+}
+
+/// Holds the file for one log line, so lines from different threads never
+/// interleave.
+pub struct LogFileWriter<'a>(MutexGuard<'a, File>);
+
+impl Write for LogFileWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// Sends each log line as one datagram to the local syslog daemon, in the
+/// BSD format it expects from local programs: `<PRI>ident[pid]: message`.
+#[derive(Clone)]
+pub struct Syslog {
+    path: PathBuf,
+    facility: Facility,
+    socket: Arc<Mutex<UnixDatagram>>,
+}
+
+impl Syslog {
+    pub fn connect(config: &SyslogConfig) -> io::Result<Self> {
+        Ok(Self {
+            path: config.socket.clone(),
+            facility: config.facility,
+            socket: Arc::new(Mutex::new(connect_datagram(&config.socket)?)),
+        })
+    }
+
+    pub fn reconnect(&self) -> io::Result<()> {
+        let socket = connect_datagram(&self.path)?;
+        *self.socket.lock().unwrap_or_else(|e| e.into_inner()) = socket;
+        Ok(())
+    }
+
+    fn send(&self, level: Level, line: &[u8]) {
+        let severity = match level {
+            Level::ERROR => 3,
+            Level::WARN => 4,
+            Level::INFO => 6,
+            Level::DEBUG | Level::TRACE => 7,
+        };
+        let mut datagram = format!(
+            "<{}>altcha-server[{}]: ",
+            self.facility.code() * 8 + severity,
+            std::process::id()
+        )
+        .into_bytes();
+        datagram.extend_from_slice(line.strip_suffix(b"\n").unwrap_or(line));
+
+        let socket = self.socket.lock().unwrap_or_else(|e| e.into_inner());
+        if socket.send(&datagram).is_err() {
+            // syslogd may have restarted; a log line is not worth more than
+            // one retry, and there is nowhere left to report the failure
+            drop(socket);
+            if self.reconnect().is_ok() {
+                let socket = self.socket.lock().unwrap_or_else(|e| e.into_inner());
+                let _ = socket.send(&datagram);
+            }
+        }
+    }
+}
+
+fn connect_datagram(path: &Path) -> io::Result<UnixDatagram> {
+    let socket = UnixDatagram::unbound()?;
+    socket.connect(path)?;
+    Ok(socket)
+}
+
+impl<'a> MakeWriter<'a> for Syslog {
+    type Writer = SyslogWriter<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        SyslogWriter {
+            syslog: self,
+            level: Level::INFO,
+            line: Vec::new(),
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        SyslogWriter {
+            syslog: self,
+            level: *meta.level(),
+            line: Vec::new(),
+        }
+    }
+}
+
+/// Collects one log line and sends it when dropped.
+pub struct SyslogWriter<'a> {
+    syslog: &'a Syslog,
+    level: Level,
+    line: Vec<u8>,
+}
+
+impl Write for SyslogWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.line.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for SyslogWriter<'_> {
+    fn drop(&mut self) {
+        if !self.line.is_empty() {
+            self.syslog.send(self.level, &self.line);
+        }
+    }
+}
+
+/// The request's `X-Request-ID` if it is a plausible ID, otherwise a new
+/// random one. Anything else is replaced so it cannot forge log lines.
+pub fn request_id(headers: &HeaderMap) -> String {
+    headers
+        .get(&REQUEST_ID)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| {
+            (1..=MAX_REQUEST_ID_LEN).contains(&id.len())
+                && id
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'))
+        })
+        .map(str::to_string)
+        .unwrap_or_else(|| hex::encode(rand::thread_rng().gen::<[u8; 16]>()))
+}
@@ -1,399 +1,504 @@
+use altcha_server::{
+    assets::{self, StaticAssets},
+    challenge::{
+        challenge_expires, generate_challenge, generate_salt, sign_challenge, verify_solution,
+        AltchaPayload, Challenge,
+    },
+    client_ip::{self, TrustedProxies},
+    config::{Cli, Config, ConfigError},
+    difficulty::DifficultyEngine,
+    error::{AppError, ErrorCode},
+    keyring::{Keyring, SharedKeyring},
+    logging::{self, REQUEST_ID},
+    metrics::Metrics,
+    page::{PageContext, PageTemplates},
+    rate_limit::{ClientIpFn, Endpoint, RateLimitLayer, RateLimiter},
+    redact::{self, Redactor},
+    replay::{self, MemoryReplayStore, ReplayStore},
+    return_to,
+    site::{self, Site, SiteRegistry},
+    token::{create_verification_token, verify_token, Claims, TokenCodec},
+    unix_now,
+};
 use axum::{
-    extract::{Query, State},
+    body::Bytes,
+    extract::{ConnectInfo, DefaultBodyLimit, MatchedPath, Path, Query, Request, State},
     http::{header, HeaderValue, Method, StatusCode},
-    response::{Html, IntoResponse, Response},
+    middleware::{self, Next},
+    response::{Html, IntoResponse, Redirect, Response},
     routing::{get, post},
     Json, Router,
 };
-use base64::{engine::general_purpose, Engine as _};
-use hmac::{Hmac, Mac};
-use rand::Rng;
-use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
-use std::sync::Arc;
+use clap::Parser;
+use serde::Deserialize;
+use std::future::IntoFuture;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use subtle::ConstantTimeEq;
 use tokio::net::TcpListener;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
 use tower::ServiceBuilder;
 use tower_http::cors::{Any, CorsLayer};
-use tracing::{info, warn};
-
-type HmacSha256 = Hmac<Sha256>;
+use tracing::{error, field, info, info_span, warn, Instrument, Span};
 
-const SECRET_KEY: &str = "ece5b7b9c637456c135dfe87f571bc5e757f5e4e51e24306c8917a69d8540206";
-const MAX_NUMBER: u32 = 50000;
-const COOKIE_NAME: &str = "altcha_verified";
+/// Largest `/api/verify` body read: the `altcha` field plus a `return_to`
+/// URL and its signature.
+const MAX_VERIFY_BODY: usize = 16 * 1024;
 
-#[derive(Clone)]
 struct AppState {
-    secret_key: String,
-}
-
-#[derive(Serialize)]
-struct Challenge {
-    algorithm: String,
-    challenge: String,
-    maxnumber: u32,
-    salt: String,
-    signature: String,
+    cli: Cli,
+    settings: RwLock<Arc<Settings>>,
+    keys: SharedKeyring,
+    assets: StaticAssets,
+    replay: Arc<dyn ReplayStore>,
+    difficulty: Arc<DifficultyEngine>,
+    rate_limiter: Arc<RateLimiter>,
+    metrics: Metrics,
+    started: Instant,
 }
 
-#[derive(Deserialize)]
-struct VerifyRequest {
-    altcha: String,
-}
-
-#[derive(Deserialize)]
-struct AltchaPayload {
-    #[allow(dead_code)]
-    algorithm: String,
-    challenge: String,
-    number: u32,
-    salt: String,
-    signature: String,
-}
-
-#[derive(Deserialize)]
-struct ChallengePageQuery {
-    return_to: Option<String>,
-}
+impl AppState {
+    /// Snapshot of the current settings. Handlers take one per request, so a
+    /// reload never changes the configuration under a request in flight.
+    fn settings(&self) -> Arc<Settings> {
+        self.settings
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
 
-fn generate_salt() -> String {
-    let mut rng = rand::thread_rng();
-    let bytes: [u8; 16] = rng.gen();
-    hex::encode(bytes)
-}
+    /// Re-reads the configuration and swaps in its settings, keyring and
+    /// difficulty tiers. Everything stays in place if the new configuration
+    /// does not load.
+    fn reload(&self) -> Result<Arc<Keyring>, ConfigError> {
+        let settings = Settings::from_config(Config::load(&self.cli)?)?;
+        let config = &settings.config;
+        let current = self.settings();
+        if config.server.bind != current.config.server.bind
+            || config.admin.bind != current.config.admin.bind
+            || config.replay.path != current.config.replay.path
+            || config.replay.flush_interval_secs != current.config.replay.flush_interval_secs
+            || config.logging.format != current.config.logging.format
+            || config.logging.output != current.config.logging.output
+            || config.logging.path != current.config.logging.path
+            || config.logging.syslog != current.config.logging.syslog
+        {
+            warn!(
+                "server.bind, admin.bind, replay and logging output changes take effect \
+                 after a restart"
+            );
+        }
 
-fn generate_challenge(
-    salt: &str,
-    _secret_key: &str,
-) -> Result<(String, u32), Box<dyn std::error::Error>> {
-    // ALTCHA proof-of-work: generate a secret number and create challenge from it
-    let mut rng = rand::thread_rng();
-    let secret_number: u32 = rng.gen_range(0..MAX_NUMBER);
-
-    // Create challenge by hashing salt + secret_number
-    let work_data = format!("{}{}", salt, secret_number);
-    let mut hasher = Sha256::new();
-    hasher.update(work_data.as_bytes());
-    let hash = hasher.finalize();
-    let challenge = hex::encode(hash);
-
-    Ok((challenge, secret_number))
+        self.difficulty.reconfigure(config.difficulty.clone());
+        self.rate_limiter.reconfigure(config.rate_limit.clone());
+        self.keys.replace(Keyring::from_config(config));
+        install_redactor(config, &self.keys.load());
+        let hosts = settings.sites.hosts().join(", ");
+        *self.settings.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(settings);
+
+        let keyring = self.keys.load();
+        info!(
+            domains = %hosts,
+            active_key = %keyring.active().id,
+            keys = ?keyring.ids(),
+            "Reloaded configuration"
+        );
+        Ok(keyring)
+    }
 }
 
-fn sign_challenge(
-    challenge: &str,
-    salt: &str,
-    secret_key: &str,
-) -> Result<String, Box<dyn std::error::Error>> {
-    let mut mac = HmacSha256::new_from_slice(secret_key.as_bytes())?;
-    mac.update(format!("{}{}", challenge, salt).as_bytes());
-    let result = mac.finalize();
-    Ok(hex::encode(result.into_bytes()))
+/// Points log redaction at the configured modes, with pseudonyms keyed by
+/// the active signing key. Rotating the key changes every pseudonym from
+/// then on.
+fn install_redactor(config: &Config, keyring: &Keyring) {
+    redact::install(Redactor::new(
+        config.logging.redact.clone(),
+        keyring.active().pseudonym_key(),
+    ));
 }
 
-// 🔐 SECURITY: Create cryptographically signed verification tokens
-fn create_verification_token(
-    client_ip: &str,
-    domain: &str,
-    secret_key: &str,
-) -> Result<String, Box<dyn std::error::Error>> {
-    use std::time::{SystemTime, UNIX_EPOCH};
-
-    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
-    let expires = timestamp + 86400; // 24 hours
-
-    // Create payload: ip|domain|expires
-    let payload = format!("{}|{}|{}", client_ip, domain, expires);
-
-    // Sign the payload with HMAC-SHA256
-    let mut mac = HmacSha256::new_from_slice(secret_key.as_bytes())?;
-    mac.update(payload.as_bytes());
-    let signature = hex::encode(mac.finalize().into_bytes());
-
-    // Create token: base64(payload):signature
-    let token = format!(
-        "{}:{}",
-        general_purpose::STANDARD.encode(payload.as_bytes()),
-        signature
-    );
-
-    Ok(token)
+/// Everything built from the configuration file. A reload replaces it as a
+/// whole.
+struct Settings {
+    config: Config,
+    proxies: TrustedProxies,
+    sites: SiteRegistry,
+    pages: PageTemplates,
+    tokens: TokenCodec,
 }
 
-// 🔍 SECURITY: Verify signed tokens cryptographically
-fn verify_token(
-    token: &str,
-    client_ip: &str,
-    domain: &str,
-    secret_key: &str,
-) -> Result<bool, Box<dyn std::error::Error>> {
-    use std::time::{SystemTime, UNIX_EPOCH};
-
-    // Parse token format: base64(payload):signature
-    let parts: Vec<&str> = token.split(':').collect();
-    if parts.len() != 2 {
-        return Ok(false); // Invalid format - not base64:signature
-    }
-
-    let payload_b64 = parts[0];
-    let provided_signature = parts[1];
-
-    // Decode payload
-    let payload_bytes = general_purpose::STANDARD.decode(payload_b64)?;
-    let payload = String::from_utf8(payload_bytes)?;
-
-    // Verify HMAC signature
-    let mut mac = HmacSha256::new_from_slice(secret_key.as_bytes())?;
-    mac.update(payload.as_bytes());
-    let expected_signature = hex::encode(mac.finalize().into_bytes());
-
-    if provided_signature != expected_signature {
-        warn!("Invalid token signature from {}", client_ip);
-        return Ok(false); // Signature verification failed
-    }
-
-    // Parse payload: ip|domain|expires
-    let payload_parts: Vec<&str> = payload.split('|').collect();
-    if payload_parts.len() != 3 {
-        return Ok(false);
+impl Settings {
+    fn from_config(config: Config) -> Result<Self, ConfigError> {
+        let proxies = TrustedProxies::parse(&config.proxy.trusted, config.proxy.header)
+            .map_err(|e| ConfigError::Invalid(format!("proxy.trusted: {}", e)))?;
+        let sites = SiteRegistry::from_config(&config);
+        let pages = PageTemplates::load(&sites, config.server.dev_mode)?;
+        let tokens = TokenCodec::new(config.token.accept_legacy_until);
+        Ok(Self {
+            config,
+            proxies,
+            sites,
+            pages,
+            tokens,
+        })
     }
 
-    let token_ip = payload_parts[0];
-    let token_domain = payload_parts[1];
-    let expires: u64 = payload_parts[2].parse()?;
-
-    // Check expiration
-    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
-    if now > expires {
-        info!("Expired token from {}", client_ip);
-        return Ok(false); // Token expired
+    /// The client's IP, also recorded (redacted) on the request span.
+    fn client_ip(&self, peer: SocketAddr, headers: &axum::http::HeaderMap) -> String {
+        let ip = client_ip::resolve(peer, headers, &self.proxies).to_string();
+        Span::current().record("ip", field::display(redact::ip(&ip)));
+        ip
     }
 
-    // Check IP and domain binding
-    if token_ip != client_ip || token_domain != domain {
-        warn!(
-            "Token IP/domain mismatch: token={}@{}, actual={}@{}",
-            token_ip, token_domain, client_ip, domain
-        );
-        return Ok(false); // IP or domain mismatch
+    /// The registered site for `host`; requests for any other host are
+    /// rejected with `status`.
+    fn site(&self, host: &str, status: StatusCode) -> Result<&Site, AppError> {
+        let site = self.sites.lookup(host).ok_or_else(|| {
+            warn!(host, "Rejected request for unknown host");
+            AppError::UnknownHost(status)
+        })?;
+        Span::current().record("site", site.host.as_str());
+        Ok(site)
     }
-
-    Ok(true) // All validations passed
 }
 
-fn verify_solution(
-    payload: &AltchaPayload,
-    secret_key: &str,
-) -> Result<bool, Box<dyn std::error::Error>> {
-    // Verify signature
-    let expected_signature = sign_challenge(&payload.challenge, &payload.salt, secret_key)?;
-    if payload.signature != expected_signature {
-        warn!(
-            "Signature mismatch. Expected: {}, Got: {}",
-            expected_signature, payload.signature
-        );
-        return Ok(false);
-    }
-
-    // Verify proof of work - hash must EQUAL the challenge (not start with)
-    let work_data = format!("{}{}", payload.salt, payload.number);
-    let mut hasher = Sha256::new();
-    hasher.update(work_data.as_bytes());
-    let hash = hasher.finalize();
-    let hash_hex = hex::encode(hash);
-
-    // Check if hash exactly matches challenge
-    let challenge_met = hash_hex == payload.challenge;
-
-    info!(
-        "Verifying: salt={}, number={}, hash={}, challenge={}, matches={}",
-        payload.salt,
-        payload.number,
-        &hash_hex[..8],
-        &payload.challenge[..8],
-        challenge_met
-    );
-
-    if !challenge_met {
-        warn!(
-            "Proof of work failed. Hash: {}, Challenge: {}",
-            &hash_hex[..8],
-            &payload.challenge[..8]
-        );
-        return Ok(false);
-    }
-
-    info!("ALTCHA verification successful. Hash matches challenge exactly");
-    Ok(true)
+#[derive(Deserialize)]
+struct VerifyRequest {
+    altcha: String,
+    /// The challenge page's return_to and its signature, echoed back so the
+    /// redirect after verification cannot be swapped out
+    #[serde(default)]
+    return_to: Option<String>,
+    #[serde(default)]
+    return_sig: Option<String>,
 }
 
-fn get_host_domain(headers: &axum::http::HeaderMap) -> String {
-    if let Some(host) = headers.get("host") {
-        if let Ok(host_str) = host.to_str() {
-            // Remove port if present
-            let host_without_port = host_str.split(':').next().unwrap_or(host_str);
-            return host_without_port.to_string();
-        }
-    }
-    "localhost".to_string()
+#[derive(Deserialize)]
+struct ChallengePageQuery {
+    return_to: Option<String>,
 }
 
-fn get_client_ip(headers: &axum::http::HeaderMap) -> String {
-    // Check X-Forwarded-For header first (from nginx)
-    if let Some(xff) = headers.get("x-forwarded-for") {
-        if let Ok(xff_str) = xff.to_str() {
-            if let Some(first_ip) = xff_str.split(',').next() {
-                return first_ip.trim().to_string();
-            }
-        }
-    }
+// Host header as sent, including any port
+// 🔐 SECURITY: The host ends up in cookie attributes and redirects, so
+// anything but a hostname and port is rejected rather than passed on
+fn get_host(headers: &axum::http::HeaderMap) -> Result<&str, AppError> {
+    let Some(host) = headers.get(header::HOST) else {
+        return Ok("localhost");
+    };
+    host.to_str()
+        .ok()
+        .filter(|host| site::host_header_name(host).is_some())
+        .ok_or(AppError::InvalidHost)
+}
 
-    // Check X-Real-IP header
-    if let Some(xri) = headers.get("x-real-ip") {
-        if let Ok(xri_str) = xri.to_str() {
-            return xri_str.to_string();
-        }
-    }
+fn get_host_domain(headers: &axum::http::HeaderMap) -> Result<String, AppError> {
+    let host = get_host(headers)?;
+    // Remove port if present
+    Ok(host.split(':').next().unwrap_or(host).to_string())
+}
 
-    // Fallback
-    "unknown".to_string()
+// User-Agent for user_agent token binding; missing or non-ASCII reads as empty
+fn get_user_agent(headers: &axum::http::HeaderMap) -> &str {
+    headers
+        .get(header::USER_AGENT)
+        .and_then(|ua| ua.to_str().ok())
+        .unwrap_or("")
 }
 
 async fn challenge_handler(
     State(state): State<Arc<AppState>>,
-) -> Result<Json<Challenge>, StatusCode> {
-    info!("Challenge endpoint called");
-
-    let salt = generate_salt();
-    let (challenge, secret_number) = match generate_challenge(&salt, &state.secret_key) {
-        Ok(result) => result,
-        Err(e) => {
-            warn!("Failed to generate challenge: {}", e);
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
-        }
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: axum::http::HeaderMap,
+) -> Result<Json<Challenge>, AppError> {
+    let settings = state.settings();
+    let client_ip = settings.client_ip(peer, &headers);
+    let host_domain = get_host_domain(&headers)?;
+    let site = settings.site(&host_domain, StatusCode::NOT_FOUND)?;
+    let algorithm = site.algorithm;
+    let keyring = state.keys.load();
+
+    state.difficulty.record(&client_ip);
+    let max_number = state.difficulty.max_number_for(&client_ip, site.max_number);
+
+    let expires = unix_now().saturating_add(settings.config.challenge.ttl_secs);
+    let salt = generate_salt(expires, max_number);
+    let challenge = generate_challenge(algorithm, &salt, max_number);
+    state.metrics.challenge_issued(&site.host);
+
+    let signature = sign_challenge(
+        algorithm,
+        &challenge,
+        &salt,
+        keyring.active().challenge_key(),
+    );
+    let response = Challenge {
+        algorithm,
+        challenge,
+        maxnumber: max_number,
+        salt,
+        signature,
     };
 
-    match sign_challenge(&challenge, &salt, &state.secret_key) {
-        Ok(signature) => {
-            let response = Challenge {
-                algorithm: "SHA-256".to_string(),
-                challenge: challenge.clone(),
-                maxnumber: MAX_NUMBER,
-                salt: salt.clone(),
-                signature: signature.clone(),
-            };
-
-            // Debug the response
-            info!(
-                "Generated challenge response: algorithm={}, challenge={}, maxnumber={}, salt={}, signature={}, secret_number={}",
-                response.algorithm,
-                &response.challenge[..8],
-                response.maxnumber,
-                response.salt,
-                &response.signature[..8],
-                secret_number
-            );
+    // Debug the response
+    info!(
+        domain = %host_domain,
+        algorithm = %response.algorithm,
+        challenge = %redact::salt(&response.challenge),
+        maxnumber = response.maxnumber,
+        salt = %redact::salt(&response.salt),
+        signature = %redact::salt(&response.signature),
+        "Issued challenge"
+    );
 
-            Ok(Json(response))
-        }
-        Err(e) => {
-            warn!("Failed to generate challenge signature: {}", e);
-            Err(StatusCode::INTERNAL_SERVER_ERROR)
-        }
-    }
+    Ok(Json(response))
 }
 
 async fn verify_handler(
     State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
     headers: axum::http::HeaderMap,
-    Json(req): Json<VerifyRequest>,
-) -> Result<Response, StatusCode> {
-    let client_ip = get_client_ip(&headers);
-    let host_domain = get_host_domain(&headers);
+    body: Bytes,
+) -> Result<Response, AppError> {
+    let settings = state.settings();
+    let client_ip = settings.client_ip(peer, &headers);
+    let host_domain = get_host_domain(&headers)?;
     info!(
-        "Verification attempt from IP: {} for domain: {}",
-        client_ip, host_domain
+        domain = %host_domain,
+        "Verification attempt"
     );
+    let site = settings.site(&host_domain, StatusCode::NOT_FOUND)?;
+    state.difficulty.record(&client_ip);
+
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("");
+
+    // Plain HTML form post from the challenge page: redirect on success,
+    // show the page again with an error on failure
+    if content_type.starts_with("application/x-www-form-urlencoded") {
+        let req = parse_verify_form(&body);
+        let result = match &req {
+            Some(req) => verify_submission(
+                &state,
+                &settings,
+                &client_ip,
+                &host_domain,
+                site,
+                &headers,
+                req,
+            ),
+            None => {
+                warn!("Form post without an altcha field");
+                Err(AppError::InvalidRequest("missing altcha field".to_string()))
+            }
+        };
+        state.metrics.verify_result(&site.host, &result);
+        return match result {
+            // Redirect::to answers 303 See Other, so the browser follows with a GET
+            Ok((cookie, redirect)) => {
+                Ok(([(header::SET_COOKIE, cookie)], Redirect::to(&redirect)).into_response())
+            }
+            Err(e) if e.status().is_client_error() => {
+                // Only a return_to we signed survives the round trip
+                let return_to = req.as_ref().and_then(|req| {
+                    return_to::signed(
+                        req.return_to.as_deref(),
+                        req.return_sig.as_deref(),
+                        &host_domain,
+                        state.keys.load().keys(),
+                    )
+                });
+                let page = render_challenge_page(
+                    &state,
+                    &settings,
+                    &headers,
+                    site,
+                    return_to,
+                    Some(site.string("verify_failed")),
+                )?;
+                let mut page = (StatusCode::BAD_REQUEST, page).into_response();
+                page.extensions_mut().insert(ErrorCode(e.code()));
+                Ok(page)
+            }
+            Err(e) => Err(e),
+        };
+    }
 
-    // Decode base64 payload
-    let payload_bytes = match general_purpose::STANDARD.decode(&req.altcha) {
-        Ok(bytes) => bytes,
-        Err(e) => {
-            warn!("Invalid base64 from {}: {}", client_ip, e);
-            return Err(StatusCode::BAD_REQUEST);
+    let result = match serde_json::from_slice::<VerifyRequest>(&body) {
+        Ok(req) => verify_submission(
+            &state,
+            &settings,
+            &client_ip,
+            &host_domain,
+            site,
+            &headers,
+            &req,
+        ),
+        Err(e) if content_type.starts_with("application/json") => {
+            warn!(error = %e, "Invalid verify request");
+            Err(AppError::InvalidRequest(e.to_string()))
         }
+        Err(_) => Err(AppError::UnsupportedMediaType),
     };
+    state.metrics.verify_result(&site.host, &result);
+    let (cookie, redirect) = result?;
+
+    let mut response = Json(serde_json::json!({
+        "status": "verified",
+        "message": "Verification successful",
+        "redirect": redirect
+    }))
+    .into_response();
+    response.headers_mut().insert(header::SET_COOKIE, cookie);
+    Ok(response)
+}
 
-    // Parse JSON payload
-    let payload: AltchaPayload = match serde_json::from_slice(&payload_bytes) {
-        Ok(p) => p,
-        Err(e) => {
-            warn!("Invalid JSON payload from {}: {}", client_ip, e);
-            return Err(StatusCode::BAD_REQUEST);
+fn parse_verify_form(body: &[u8]) -> Option<VerifyRequest> {
+    let mut altcha = None;
+    let mut return_to = None;
+    let mut return_sig = None;
+    for (name, value) in url::form_urlencoded::parse(body) {
+        match name.as_ref() {
+            "altcha" => altcha = Some(value.into_owned()),
+            "return_to" => return_to = Some(value.into_owned()),
+            "return_sig" => return_sig = Some(value.into_owned()),
+            _ => {}
         }
-    };
+    }
+    Some(VerifyRequest {
+        altcha: altcha.filter(|altcha| !altcha.is_empty())?,
+        return_to,
+        return_sig,
+    })
+}
+
+/// Checks a submitted solution and, if it is good, returns the verification
+/// cookie and where to send the visitor.
+fn verify_submission(
+    state: &AppState,
+    settings: &Settings,
+    client_ip: &str,
+    host_domain: &str,
+    site: &Site,
+    headers: &axum::http::HeaderMap,
+    req: &VerifyRequest,
+) -> Result<(HeaderValue, String), AppError> {
+    // Decode and validate the payload before doing any hashing
+    let payload = AltchaPayload::decode(&req.altcha).map_err(|e| {
+        warn!(error = %e, "Invalid ALTCHA payload");
+        AppError::from(e)
+    })?;
 
     // Verify the solution
-    match verify_solution(&payload, &state.secret_key) {
-        Ok(true) => {
-            info!(
-                "ALTCHA verification successful for IP: {} on domain: {}",
-                client_ip, host_domain
-            );
+    let keyring = state.keys.load();
+    if let Err(e) = verify_solution(&payload, keyring.keys()) {
+        warn!(
+            domain = %host_domain,
+            error = %e,
+            "ALTCHA verification failed"
+        );
+        return Err(e.into());
+    }
 
-            // 🔐 SECURITY: Create signed verification token (not simple "true")
-            let token = match create_verification_token(&client_ip, &host_domain, &state.secret_key)
-            {
-                Ok(t) => t,
-                Err(e) => {
-                    warn!("Failed to create verification token: {}", e);
-                    return Err(StatusCode::INTERNAL_SERVER_ERROR);
-                }
-            };
+    // 🔐 SECURITY: Each solved challenge can only be redeemed once
+    let replay_key = replay::replay_key(&payload.salt, &payload.signature);
+    // Remember it until the challenge itself expires; after that
+    // verify_solution rejects it anyway. It has already rejected salts
+    // without an expiry, so the fallback only errs towards remembering.
+    let expires_at = challenge_expires(&payload.salt).unwrap_or(u64::MAX);
+    if !state.replay.mark_spent(&replay_key, expires_at) {
+        warn!(
+            domain = %host_domain,
+            salt = %redact::salt(&payload.salt),
+            "Replayed ALTCHA solution"
+        );
+        return Err(AppError::Replayed);
+    }
 
-            // Set secure cookie with signed token
-            let cookie_value = format!(
-                "{}={}; Path=/; Domain={}; HttpOnly; Secure; SameSite=Strict; Max-Age=86400",
-                COOKIE_NAME, token, host_domain
-            );
+    info!(
+        domain = %host_domain,
+        "ALTCHA verification successful"
+    );
 
-            let mut response = Json(serde_json::json!({
-                "status": "verified",
-                "message": "Verification successful"
-            }))
-            .into_response();
+    // 🔐 SECURITY: Create signed verification token (not simple "true")
+    let token = create_verification_token(
+        &settings.tokens,
+        site.binding,
+        client_ip,
+        get_user_agent(headers),
+        host_domain,
+        keyring.active(),
+        site.token_ttl_secs,
+    )
+    .map_err(|e| {
+        error!(error = %e, "Failed to create verification token");
+        AppError::Internal
+    })?;
+
+    // Set secure cookie with signed token
+    let cookie_value = format!(
+        "{}={}; Path=/; Domain={}; HttpOnly; Secure; SameSite=Strict; Max-Age={}",
+        site.cookie_name, token, host_domain, site.token_ttl_secs
+    );
 
-            response.headers_mut().insert(
-                header::SET_COOKIE,
-                HeaderValue::from_str(&cookie_value).unwrap(),
-            );
+    // 🔐 SECURITY: Redirect only to a return_to this server signed
+    let redirect = return_to::redirect(
+        req.return_to.as_deref(),
+        req.return_sig.as_deref(),
+        get_host(headers)?,
+        host_domain,
+        keyring.keys(),
+    );
 
-            Ok(response)
-        }
-        Ok(false) => {
-            warn!(
-                "ALTCHA verification failed for IP: {} on domain: {}",
-                client_ip, host_domain
-            );
-            Err(StatusCode::BAD_REQUEST)
-        }
-        Err(e) => {
-            warn!(
-                "Error verifying solution from {} on domain {}: {}",
-                client_ip, host_domain, e
-            );
-            Err(StatusCode::INTERNAL_SERVER_ERROR)
-        }
-    }
+    let cookie = HeaderValue::from_str(&cookie_value).map_err(|e| {
+        error!(domain = %host_domain, error = %e, "Invalid verification cookie");
+        AppError::Internal
+    })?;
+    Ok((cookie, redirect))
 }
 
 // 🔍 SECURITY ENDPOINT: nginx calls this to validate tokens cryptographically
 async fn validate_handler(
     State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
     headers: axum::http::HeaderMap,
-) -> Result<StatusCode, StatusCode> {
-    let client_ip = get_client_ip(&headers);
-    let host_domain = get_host_domain(&headers);
+) -> Result<StatusCode, AppError> {
+    let settings = state.settings();
+    let client_ip = settings.client_ip(peer, &headers);
+    // nginx treats anything but 2xx, 401 and 403 as an error, so a bad Host
+    // is refused like an unknown one
+    let host_domain =
+        get_host_domain(&headers).map_err(|_| AppError::UnknownHost(StatusCode::FORBIDDEN))?;
+
+    // 403 rather than 401: no cookie would help, this host is not ours
+    let site = settings.site(&host_domain, StatusCode::FORBIDDEN)?;
+
+    let result = validate_cookie(&state, &settings, &client_ip, &host_domain, site, &headers);
+    state.metrics.validate_result(&site.host, &result);
+    let claims = result?; // nginx gets 401 → blocks access
+
+    // Legacy tokens carry no issue time
+    if claims.iat > 0 {
+        state
+            .metrics
+            .token_age(&site.host, unix_now().saturating_sub(claims.iat));
+    }
+    Ok(StatusCode::OK) // nginx gets 200 → allows access
+}
+
+/// Finds the site's verification cookie and checks the token in it.
+fn validate_cookie(
+    state: &AppState,
+    settings: &Settings,
+    client_ip: &str,
+    host_domain: &str,
+    site: &Site,
+    headers: &axum::http::HeaderMap,
+) -> Result<Claims, AppError> {
+    let cookie_name = &site.cookie_name;
 
     // Extract token from cookie header
     let token = if let Some(cookie_header) = headers.get("cookie") {
@@ -402,7 +507,7 @@ async fn validate_handler(
             let mut found_token = None;
             for cookie in cookie_str.split(';') {
                 let cookie = cookie.trim();
-                if let Some(value) = cookie.strip_prefix(&format!("{}=", COOKIE_NAME)) {
+                if let Some(value) = cookie.strip_prefix(&format!("{}=", cookie_name)) {
                     found_token = Some(value.to_string());
                     break;
                 }
@@ -410,263 +515,521 @@ async fn validate_handler(
             match found_token {
                 Some(token) => token,
                 None => {
-                    info!("No {} cookie found from {}", COOKIE_NAME, client_ip);
-                    return Err(StatusCode::UNAUTHORIZED);
+                    info!(
+                        cookie = %cookie_name,
+                        "No verification cookie"
+                    );
+                    return Err(AppError::MissingToken);
                 }
             }
         } else {
-            warn!("Invalid cookie header from {}", client_ip);
-            return Err(StatusCode::UNAUTHORIZED);
+            warn!("Invalid cookie header");
+            return Err(AppError::MissingToken);
         }
     } else {
-        info!("No cookie header from {}", client_ip);
-        return Err(StatusCode::UNAUTHORIZED);
+        info!("No cookie header");
+        return Err(AppError::MissingToken);
     };
 
     // 🔐 SECURITY: Cryptographically validate the token
-    match verify_token(&token, &client_ip, &host_domain, &state.secret_key) {
-        Ok(true) => {
+    let keyring = state.keys.load();
+    match verify_token(
+        &settings.tokens,
+        &token,
+        client_ip,
+        get_user_agent(headers),
+        host_domain,
+        keyring.keys(),
+    ) {
+        Ok(claims) => {
             info!(
-                "Valid token for IP: {} on domain: {}",
-                client_ip, host_domain
+                domain = %host_domain,
+                "Valid token"
             );
-            Ok(StatusCode::OK) // nginx gets 200 → allows access
+            Ok(claims)
         }
-        Ok(false) => {
+        Err(e) => {
             warn!(
-                "Invalid token from IP: {} on domain: {}",
-                client_ip, host_domain
+                domain = %host_domain,
+                token = %redact::token(&token),
+                error = %e,
+                "Invalid token"
             );
-            Err(StatusCode::UNAUTHORIZED) // nginx gets 401 → blocks access
+            Err(e.into())
+        }
+    }
+}
+
+// 🔐 ADMIN: requires `Authorization: Bearer <admin.token>`; without a token
+// configured the route does not exist
+async fn require_admin_token(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let settings = state.settings();
+    let Some(expected) = settings.config.admin.token.as_deref() else {
+        return Err(AppError::NotFound); // Admin endpoints disabled
+    };
+    let headers = request.headers();
+    let provided = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or("");
+    if !bool::from(provided.as_bytes().ct_eq(expected.as_bytes())) {
+        warn!(
+            ip = %redact::ip(&settings.client_ip(peer, headers)),
+            path = request.uri().path(),
+            "Unauthorized admin request"
+        );
+        return Err(AppError::Unauthorized);
+    }
+    Ok(next.run(request).await)
+}
+
+// 🔐 ADMIN ENDPOINT: reload configuration and signing keys without a restart
+async fn reload_keys_handler(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    match state.reload() {
+        Ok(keyring) => Ok(Json(serde_json::json!({
+            "status": "reloaded",
+            "active": keyring.active().id,
+            "keys": keyring.ids(),
+        }))),
+        Err(e) => {
+            error!(error = %e, "Reload failed, keeping current configuration");
+            Err(AppError::Internal)
         }
+    }
+}
+
+// 🔐 ADMIN ENDPOINT: Prometheus metrics
+async fn metrics_handler(State(state): State<Arc<AppState>>) -> Result<Response, AppError> {
+    match state.metrics.render() {
+        Ok(text) => Ok(([(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)], text).into_response()),
         Err(e) => {
-            warn!("Token validation error for {}: {}", client_ip, e);
-            Err(StatusCode::UNAUTHORIZED) // nginx gets 401 → blocks access
+            error!(error = %e, "Failed to render metrics");
+            Err(AppError::Internal)
         }
     }
 }
 
+// Runs each request in a span carrying its ID, which handlers fill in with
+// the site and client, and logs how it ended
+async fn trace_request(request: Request, next: Next) -> Response {
+    let request_id = logging::request_id(request.headers());
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_default();
+    let span = info_span!(
+        "request",
+        request_id = %request_id,
+        method = %request.method(),
+        route,
+        site = field::Empty,
+        ip = field::Empty,
+    );
+
+    let start = Instant::now();
+    let mut response = next.run(request).instrument(span.clone()).await;
+    let status = response.status();
+    let outcome = if status.is_server_error() {
+        "error"
+    } else if status.is_client_error() {
+        "rejected"
+    } else {
+        "ok"
+    };
+    let reason = response.extensions().get::<ErrorCode>().map(|code| code.0);
+    span.in_scope(|| {
+        info!(
+            status = status.as_u16(),
+            outcome,
+            reason,
+            latency_ms = start.elapsed().as_secs_f64() * 1000.0,
+            "Request finished"
+        )
+    });
+
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(REQUEST_ID.clone(), value);
+    }
+    response
+}
+
+// Times every routed request for the request_duration histogram
+async fn track_latency(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_default();
+    let start = Instant::now();
+    let response = next.run(request).await;
+    state.metrics.request_duration(
+        &route,
+        response.status().as_u16(),
+        start.elapsed().as_secs_f64(),
+    );
+    response
+}
+
 async fn challenge_page_handler(
+    State(state): State<Arc<AppState>>,
     headers: axum::http::HeaderMap,
     Query(params): Query<ChallengePageQuery>,
-) -> Html<String> {
-    let host = headers
-        .get("host")
-        .and_then(|h| h.to_str().ok())
-        .unwrap_or("localhost");
-
-    let return_to = params
-        .return_to
-        .unwrap_or_else(|| format!("https://{}/", host));
-
-    // Customize content based on domain
-    let (page_title, service_name, description) = match host {
-        "oar.princeton.edu" => (
-            "Verification Required - Princeton OAR",
-            "Princeton OAR (Open Access Repository)",
-            "Princeton OAR repository",
-        ),
-        "dataspace.princeton.edu" => (
-            "Verification Required - Princeton DataSpace",
-            "Princeton DataSpace",
-            "Princeton DataSpace repository",
+) -> Result<Response, AppError> {
+    let settings = state.settings();
+    let site = settings.site(&get_host_domain(&headers)?, StatusCode::NOT_FOUND)?;
+    render_challenge_page(
+        &state,
+        &settings,
+        &headers,
+        site,
+        params.return_to.as_deref(),
+        None,
+    )
+}
+
+/// Renders the challenge page for `site`, optionally with an error from a
+/// failed form submission.
+fn render_challenge_page(
+    state: &AppState,
+    settings: &Settings,
+    headers: &axum::http::HeaderMap,
+    site: &Site,
+    return_to: Option<&str>,
+    error: Option<&str>,
+) -> Result<Response, AppError> {
+    let host = get_host(headers)?;
+    let host_domain = get_host_domain(headers)?;
+
+    // 🔐 SECURITY: Only redirect back to this site (or its return_hosts)
+    let return_to = return_to::resolve(return_to, host, site, settings.config.server.dev_mode);
+    let return_sig = return_to::sign(&return_to, &host_domain, state.keys.load().active());
+
+    let widget = state.assets.widget();
+    let script = state.assets.page_script();
+    let page = PageContext {
+        host,
+        return_to: &return_to,
+        return_sig: &return_sig,
+        challenge_url: "/api/challenge",
+        verify_url: "/api/verify",
+        widget_url: widget.map(|asset| asset.url.as_str()),
+        widget_integrity: widget.map(|asset| asset.integrity.as_str()),
+        script_url: &script.url,
+        script_integrity: &script.integrity,
+        error,
+    };
+    match settings.pages.render(site, &page) {
+        Ok(html) => Ok((
+            [(
+                header::CONTENT_SECURITY_POLICY,
+                assets::content_security_policy(&return_to),
+            )],
+            Html(html),
+        )
+            .into_response()),
+        Err(e) => {
+            error!(host = %site.host, error = %format_args!("{:#}", e), "Failed to render challenge page");
+            Err(AppError::Internal)
+        }
+    }
+}
+
+// Public: self-hosted ALTCHA widget and page script
+async fn static_handler(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> Result<Response, AppError> {
+    state
+        .assets
+        .get(&name)
+        .map(|asset| asset.response())
+        .ok_or(AppError::NotFound)
+}
+
+// Public: liveness probe; answering at all is the check
+async fn healthz_handler(State(state): State<Arc<AppState>>) -> Json<serde_json::Value> {
+    Json(serde_json::json!({
+        "status": "ok",
+        "version": env!("CARGO_PKG_VERSION"),
+        "uptime_secs": state.started.elapsed().as_secs(),
+    }))
+}
+
+// Public: readiness probe; 503 until every check passes
+async fn readyz_handler(State(state): State<Arc<AppState>>) -> Response {
+    let settings = state.settings();
+    let checks = [
+        (
+            "config",
+            settings.config.validate().map_err(|e| e.to_string()),
         ),
-        _ => (
-            "Verification Required - Princeton University",
-            "Princeton University",
-            "Princeton University resources",
+        ("keys", keys_health(&settings.config, &state.keys.load())),
+        ("replay_store", state.replay.health()),
+        (
+            "widget",
+            state
+                .assets
+                .widget()
+                .map(|_| ())
+                .ok_or_else(|| "ALTCHA widget not embedded".to_string()),
         ),
+    ];
+
+    let ready = checks.iter().all(|(_, result)| result.is_ok());
+    let details: serde_json::Map<String, serde_json::Value> = checks
+        .iter()
+        .map(|(name, result)| {
+            let detail = match result {
+                Ok(()) => "ok",
+                Err(e) => e.as_str(),
+            };
+            (name.to_string(), detail.into())
+        })
+        .collect();
+    let body = Json(serde_json::json!({
+        "status": if ready { "ready" } else { "not_ready" },
+        "checks": details,
+    }));
+
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
     };
+    (status, body).into_response()
+}
 
-    let html = format!(
-        r#"
-<!DOCTYPE html>
-<html lang="en">
-<head>
-    <title>{}</title>
-    <meta charset="utf-8">
-    <meta name="viewport" content="width=device-width, initial-scale=1">
-    <script type="module" src="https://cdn.jsdelivr.net/npm/altcha@latest/dist/altcha.min.js"></script>
-    <style>
-        body {{
-            font-family: -apple-system, BlinkMacSystemFont, 'Segoe UI', Roboto, sans-serif;
-            max-width: 600px;
-            margin: 0 auto;
-            padding: 20px;
-            background: #f5f5f5;
-            line-height: 1.6;
-        }}
-        .container {{
-            background: white;
-            padding: 40px;
-            border-radius: 8px;
-            box-shadow: 0 2px 10px rgba(0,0,0,0.1);
-            text-align: center;
-            margin-top: 50px;
-        }}
-        .logo {{
-            color: #e87722;
-            margin-bottom: 30px;
-            font-size: 2em;
-        }}
-        h2 {{ color: #333; margin-bottom: 10px; }}
-        p {{ color: #666; margin-bottom: 30px; }}
-        altcha-widget {{
-            margin: 20px 0;
-            display: block;
-        }}
-        button {{
-            background: #e87722;
-            color: white;
-            border: none;
-            padding: 12px 24px;
-            border-radius: 4px;
-            cursor: pointer;
-            font-size: 16px;
-            margin-top: 15px;
-            min-width: 200px;
-            transition: background-color 0.2s;
-        }}
-        button:hover:not(:disabled) {{ background: #d66a1a; }}
-        button:disabled {{ background: #ccc; cursor: not-allowed; }}
-        .info {{
-            color: #666;
-            font-size: 14px;
-            margin-top: 20px;
-            padding: 15px;
-            background: #f9f9f9;
-            border-radius: 4px;
-        }}
-        .loading {{
-            color: #666;
-            margin: 20px 0;
-            font-style: italic;
-        }}
-    </style>
-</head>
-<body>
-    <div class="container">
-        <div class="logo">
-            🎓 {}
-        </div>
-        <h2>Security Verification Required</h2>
-        <p>Please complete the verification below to continue to <strong>{}</strong></p>
-
-        <div class="loading" id="loading">Loading verification challenge...</div>
-
-        <form id="challenge-form" style="display: none;">
-            <altcha-widget
-                challengeurl="/api/challenge"
-                spamfilter="false">
-            </altcha-widget>
-            <br>
-            <button type="submit" id="submit-btn" disabled>Continue to Site</button>
-        </form>
-
-        <div class="info">
-            This verification helps protect {} from automated abuse.
-            <br><small>Powered by ALTCHA - Privacy-friendly proof of work</small>
-        </div>
-    </div>
-
-    <script>
-        const form = document.getElementById('challenge-form');
-        const submitBtn = document.getElementById('submit-btn');
-        const loading = document.getElementById('loading');
-        const widget = document.querySelector('altcha-widget');
-
-        // Show form when widget loads
-        setTimeout(() => {{
-            loading.style.display = 'none';
-            form.style.display = 'block';
-        }}, 1000);
-
-        widget.addEventListener('statechange', (ev) => {{
-            console.log('ALTCHA state:', ev.detail.state);
-
-            switch(ev.detail.state) {{
-                case 'verified':
-                    submitBtn.disabled = false;
-                    submitBtn.textContent = 'Continue to Site';
-                    submitBtn.style.background = '#2e7d32';
-                    break;
-                case 'error':
-                    submitBtn.disabled = true;
-                    submitBtn.textContent = 'Verification Failed - Try Again';
-                    setTimeout(() => widget.reset(), 2000);
-                    break;
-                case 'verifying':
-                    submitBtn.disabled = true;
-                    submitBtn.textContent = 'Verifying...';
-                    break;
-                case 'solving':
-                    submitBtn.disabled = true;
-                    submitBtn.textContent = 'Solving Challenge...';
-                    break;
-                default:
-                    submitBtn.disabled = true;
-                    submitBtn.textContent = 'Complete Verification';
-                    submitBtn.style.background = '#e87722';
-            }}
-        }});
-
-        form.addEventListener('submit', async (e) => {{
-            e.preventDefault();
-
-            // Get the payload from the form data or widget value
-            const formData = new FormData(form);
-            const payload = formData.get('altcha') || widget.value;
-
-            if (!payload) {{
-                alert('Please complete the verification first.');
-                return;
-            }}
-
-            try {{
-                const response = await fetch('/api/verify', {{
-                    method: 'POST',
-                    headers: {{'Content-Type': 'application/json'}},
-                    body: JSON.stringify({{altcha: payload}})
-                }});
-
-                if (response.ok) {{
-                    window.location.href = '{}';
-                }} else {{
-                    alert('Verification failed. Please try again.');
-                    widget.reset();
-                    submitBtn.disabled = true;
-                }}
-            }} catch (error) {{
-                alert('Error occurred. Please try again.');
-                widget.reset();
-                submitBtn.disabled = true;
-            }}
-        }});
-    </script>
-</body>
-</html>
-"#,
-        page_title, service_name, host, description, return_to
-    );
+/// The keys in use are the ones the current configuration names, with its
+/// active key signing; a reload swaps the two separately.
+fn keys_health(config: &Config, keyring: &Keyring) -> Result<(), String> {
+    let expected = Keyring::from_config(config);
+    if keyring.ids() == expected.ids() {
+        Ok(())
+    } else {
+        Err(format!(
+            "loaded keys {:?} do not match the configured keys {:?}",
+            keyring.ids(),
+            expected.ids()
+        ))
+    }
+}
 
-    Html(html)
+/// Resolves once shutdown has been requested.
+async fn stopped(mut stopping: watch::Receiver<bool>) {
+    // An error means the sender is gone, which only happens on exit
+    let _ = stopping.wait_for(|stop| *stop).await;
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = Cli::parse();
+    let config = match Config::load(&cli) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("altcha-server: {}", e);
+            std::process::exit(2);
+        }
+    };
+
     // Initialize tracing
-    tracing_subscriber::fmt::init();
+    let log_output = match logging::init(&config.logging) {
+        Ok(output) => output,
+        Err(e) => {
+            eprintln!("altcha-server: {}", e);
+            std::process::exit(2);
+        }
+    };
+
+    if config.server.dev_mode {
+        warn!("Running in development mode - do not use in production");
+    }
 
+    let bind = config.server.bind;
+    let admin_bind = config.admin.bind;
+    let drain_timeout = Duration::from_secs(config.server.shutdown_timeout_secs);
+    let difficulty = DifficultyEngine::new(config.difficulty.clone());
+    let rate_limiter = RateLimiter::new(config.rate_limit.clone());
+    let assets = StaticAssets::embedded();
+    match assets.widget() {
+        Some(widget) => info!(
+            version = assets::WIDGET_VERSION,
+            url = %widget.url,
+            "Serving ALTCHA widget"
+        ),
+        None if config.server.dev_mode => warn!(
+            "ALTCHA widget is not embedded; the challenge page will not work \
+             (run scripts/vendor-altcha.sh and rebuild)"
+        ),
+        None => {
+            eprintln!(
+                "altcha-server: the ALTCHA widget is not embedded in this build; \
+                 run scripts/vendor-altcha.sh and rebuild"
+            );
+            std::process::exit(2);
+        }
+    }
+    let replay = match &config.replay.path {
+        Some(path) => MemoryReplayStore::persistent(path.clone()),
+        None => Ok(MemoryReplayStore::new()),
+    };
+    let replay = match replay {
+        Ok(replay) => replay,
+        Err(e) => {
+            eprintln!("altcha-server: replay.path: {}", e);
+            std::process::exit(2);
+        }
+    };
+    let replay_flush = config
+        .replay
+        .path
+        .is_some()
+        .then(|| Duration::from_secs(config.replay.flush_interval_secs));
+    let keys = SharedKeyring::new(Keyring::from_config(&config));
+    info!(key = %keys.load().active().id, "Active signing key");
+    install_redactor(&config, &keys.load());
+    let settings = match Settings::from_config(config) {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("altcha-server: {}", e);
+            std::process::exit(2);
+        }
+    };
     let state = Arc::new(AppState {
-        secret_key: SECRET_KEY.to_string(),
+        cli,
+        settings: RwLock::new(Arc::new(settings)),
+        keys,
+        assets,
+        replay: Arc::new(replay),
+        difficulty: Arc::new(difficulty),
+        rate_limiter: Arc::new(rate_limiter),
+        metrics: Metrics::new()?,
+        started: Instant::now(),
     });
 
+    // 🔐 SECURITY: Save new spends as we go, so a crash cannot reopen more
+    // than the last few seconds of redeemed challenges to replay
+    if let Some(period) = replay_flush {
+        let replay = state.replay.clone();
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(period);
+            ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                ticks.tick().await;
+                if let Err(e) = replay.flush() {
+                    error!(error = %e, "Failed to save replay store");
+                }
+            }
+        });
+    }
+
+    // SIGHUP reloads the configuration and keys on the listening sockets we
+    // already have, e.g. `rcctl reload altcha_rust`
+    let mut hangup = signal(SignalKind::hangup())?;
+    let reload_state = state.clone();
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            info!("SIGHUP received, reloading configuration");
+            if let Err(e) = reload_state.reload() {
+                error!(error = %e, "Reload failed, keeping current configuration");
+            }
+        }
+    });
+
+    // SIGUSR1 reopens the log file once newsyslog or logrotate has moved it
+    let mut user1 = signal(SignalKind::user_defined1())?;
+    tokio::spawn(async move {
+        while user1.recv().await.is_some() {
+            match log_output.reopen() {
+                Ok(()) => info!("SIGUSR1 received, reopened log output"),
+                Err(e) => eprintln!("altcha-server: cannot reopen log output: {}", e),
+            }
+        }
+    });
+
+    // SIGTERM and SIGINT stop accepting connections and let in-flight
+    // requests finish, for at most server.shutdown_timeout_secs
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let (stop, stopping) = watch::channel(false);
+    tokio::spawn(async move {
+        let name = tokio::select! {
+            _ = terminate.recv() => "SIGTERM",
+            _ = interrupt.recv() => "SIGINT",
+        };
+        info!(signal = name, "Shutting down");
+        let _ = stop.send(true);
+    });
+
+    // 🔐 SECURITY: Throttle the endpoints that do hashing and HMAC work per
+    // client IP and subnet, before nginx limits are even considered
+    let limit_state = state.clone();
+    let client_ip: ClientIpFn = Arc::new(move |request: &Request| {
+        let ConnectInfo(peer) = request.extensions().get::<ConnectInfo<SocketAddr>>()?;
+        // Resolved without recording it on the span; the handler does that
+        let proxies = &limit_state.settings().proxies;
+        Some(client_ip::resolve(*peer, request.headers(), proxies).to_string())
+    });
+    let rate_limit =
+        |endpoint| RateLimitLayer::new(state.rate_limiter.clone(), endpoint, client_ip.clone());
+
     // Build the application with routes
-    let app = Router::new()
-        .route("/api/challenge", get(challenge_handler)) // Public: Generate challenges
-        .route("/api/verify", post(verify_handler)) // Public: Verify solutions
+    let public = Router::new()
+        .route(
+            "/api/challenge",
+            get(challenge_handler).layer(rate_limit(Endpoint::Challenge)),
+        ) // Public: Generate challenges
+        .route(
+            "/api/verify",
+            post(verify_handler).layer(
+                ServiceBuilder::new()
+                    .layer(rate_limit(Endpoint::Verify))
+                    .layer(DefaultBodyLimit::max(MAX_VERIFY_BODY)),
+            ),
+        ) // Public: Verify solutions
         .route("/api/validate", get(validate_handler)) // 🔐 SECURITY: nginx auth_request endpoint
         .route("/", get(challenge_page_handler)) // Public: Verification page
+        .route("/api/altcha/static/:name", get(static_handler)); // Public: Widget assets
+
+    // Admin routes and probes are polled constantly and left out of the
+    // request log
+    let public = public.route_layer(middleware::from_fn(trace_request));
+    let admin_token = || middleware::from_fn_with_state(state.clone(), require_admin_token);
+    // Metrics are open on a listener of their own, but served next to the
+    // public endpoints they need the admin token too
+    let metrics = match admin_bind {
+        Some(_) => get(metrics_handler),
+        None => get(metrics_handler).route_layer(admin_token()),
+    };
+    let admin = Router::new()
+        .route("/metrics", metrics) // 🔐 ADMIN: Prometheus metrics
+        .route(
+            "/admin/reload-keys",
+            post(reload_keys_handler).route_layer(admin_token()),
+        ); // 🔐 ADMIN: Rotate keys
+    let app = match admin_bind {
+        Some(_) => public,
+        None => public.merge(admin.clone()),
+    };
+
+    let app = app
+        .route_layer(middleware::from_fn_with_state(state.clone(), track_latency))
+        // Probes are added after the latency layer so they do not drown out
+        // real traffic in the metrics
+        .route("/healthz", get(healthz_handler)) // Public: Liveness probe
+        .route("/readyz", get(readyz_handler)) // Public: Readiness probe
         .layer(
             ServiceBuilder::new().layer(
                 CorsLayer::new()
@@ -676,14 +1039,60 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     .allow_credentials(false),
             ),
         )
-        .with_state(state);
+        .with_state(state.clone());
 
-    let listener = TcpListener::bind("127.0.0.1:8080").await?;
-    info!("ALTCHA server starting on http://127.0.0.1:8080");
+    let listener = TcpListener::bind(bind).await?;
+    info!(bind = %bind, "ALTCHA server starting");
     info!("🔐 Security endpoints: /api/validate (nginx auth_request)");
-    info!("🌐 Serving domains: dataspace.princeton.edu, oar.princeton.edu");
+    info!(
+        domains = %state.settings().sites.hosts().join(", "),
+        "🌐 Serving domains"
+    );
+
+    // /metrics and /admin on their own listener, e.g. one only the
+    // monitoring network can reach
+    if let Some(admin_bind) = admin_bind {
+        let admin = admin
+            .route_layer(middleware::from_fn_with_state(state.clone(), track_latency))
+            .with_state(state.clone());
+        let admin_listener = TcpListener::bind(admin_bind).await?;
+        info!(bind = %admin_bind, "🔐 Admin endpoints listening");
+        let stopping = stopping.clone();
+        tokio::spawn(async move {
+            if let Err(e) = axum::serve(
+                admin_listener,
+                admin.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .with_graceful_shutdown(stopped(stopping))
+            .await
+            {
+                error!(error = %e, "Admin listener failed");
+            }
+        });
+    }
 
-    axum::serve(listener, app).await?;
+    let server = axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(stopped(stopping.clone()));
+    let drained = async {
+        stopped(stopping).await;
+        tokio::time::sleep(drain_timeout).await;
+    };
+    tokio::select! {
+        result = server.into_future() => result?,
+        () = drained => warn!(
+            timeout_secs = drain_timeout.as_secs(),
+            "Requests still running, closing their connections"
+        ),
+    }
+
+    // 🔐 SECURITY: Keep spent challenges spent across the restart
+    if let Err(e) = state.replay.flush() {
+        error!(error = %e, "Failed to save replay store");
+    }
+    info!("Shutdown complete");
 
     Ok(())
 }
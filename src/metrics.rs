@@ -0,0 +1,121 @@
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry, TextEncoder,
+};
+
+use crate::error::AppError;
+
+/// Prometheus metrics, served as text from `/metrics`.
+///
+/// `host` labels carry the matched site's configured host (`*.example.org`
+/// for wildcard sites), never the raw Host header, so label cardinality is
+/// bounded by the site registry.
+pub struct Metrics {
+    registry: Registry,
+    challenges_issued: IntCounterVec,
+    verify_outcomes: IntCounterVec,
+    validate_outcomes: IntCounterVec,
+    token_age: HistogramVec,
+    request_duration: HistogramVec,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self, prometheus::Error> {
+        let registry = Registry::new_custom(Some("altcha".to_string()), None)?;
+
+        let challenges_issued = IntCounterVec::new(
+            Opts::new("challenges_issued_total", "Challenges issued, by site"),
+            &["host"],
+        )?;
+        let verify_outcomes = IntCounterVec::new(
+            Opts::new(
+                "verify_total",
+                "Solutions submitted to /api/verify, by site and result \
+                 (\"verified\" or an error code)",
+            ),
+            &["host", "result"],
+        )?;
+        let validate_outcomes = IntCounterVec::new(
+            Opts::new(
+                "validate_total",
+                "Token checks by /api/validate, by site and result \
+                 (\"valid\" or an error code)",
+            ),
+            &["host", "result"],
+        )?;
+        // One minute to one day, the default cookie lifetime
+        let token_age = HistogramVec::new(
+            HistogramOpts::new(
+                "token_age_seconds",
+                "Age of verification tokens accepted by /api/validate",
+            )
+            .buckets(vec![
+                60.0, 300.0, 900.0, 1800.0, 3600.0, 7200.0, 14400.0, 28800.0, 43200.0, 86400.0,
+            ]),
+            &["host"],
+        )?;
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "request_duration_seconds",
+                "Handler latency, by route and status",
+            ),
+            &["route", "status"],
+        )?;
+
+        registry.register(Box::new(challenges_issued.clone()))?;
+        registry.register(Box::new(verify_outcomes.clone()))?;
+        registry.register(Box::new(validate_outcomes.clone()))?;
+        registry.register(Box::new(token_age.clone()))?;
+        registry.register(Box::new(request_duration.clone()))?;
+
+        Ok(Self {
+            registry,
+            challenges_issued,
+            verify_outcomes,
+            validate_outcomes,
+            token_age,
+            request_duration,
+        })
+    }
+
+    pub fn challenge_issued(&self, host: &str) {
+        self.challenges_issued.with_label_values(&[host]).inc();
+    }
+
+    pub fn verify_result<T>(&self, host: &str, result: &Result<T, AppError>) {
+        self.verify_outcomes
+            .with_label_values(&[host, outcome(result, "verified")])
+            .inc();
+    }
+
+    pub fn validate_result<T>(&self, host: &str, result: &Result<T, AppError>) {
+        self.validate_outcomes
+            .with_label_values(&[host, outcome(result, "valid")])
+            .inc();
+    }
+
+    pub fn token_age(&self, host: &str, age_secs: u64) {
+        self.token_age
+            .with_label_values(&[host])
+            .observe(age_secs as f64);
+    }
+
+    pub fn request_duration(&self, route: &str, status: u16, secs: f64) {
+        self.request_duration
+            .with_label_values(&[route, &status.to_string()])
+            .observe(secs);
+    }
+
+    /// Everything in the Prometheus text exposition format.
+    pub fn render(&self) -> Result<String, prometheus::Error> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        String::from_utf8(buffer).map_err(|e| prometheus::Error::Msg(e.to_string()))
+    }
+}
+
+fn outcome<T>(result: &Result<T, AppError>, ok: &'static str) -> &'static str {
+    match result {
+        Ok(_) => ok,
+        Err(e) => e.code(),
+    }
+}
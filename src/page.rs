@@ -0,0 +1,148 @@
+use minijinja::{context, AutoEscape, Environment, ErrorKind};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config::ConfigError;
+use crate::site::{Site, SiteRegistry};
+
+/// The compiled-in challenge page, used by every site without a `template`.
+const DEFAULT_TEMPLATE: &str = include_str!("../templates/challenge.html");
+const DEFAULT_TEMPLATE_NAME: &str = "challenge.html";
+
+/// English defaults for the `strings` template variable. Sites replace
+/// individual entries through their `strings` table.
+pub const DEFAULT_STRINGS: &[(&str, &str)] = &[
+    ("heading", "Security Verification Required"),
+    (
+        "prompt",
+        "Please complete the verification below to continue to",
+    ),
+    ("loading", "Loading verification challenge..."),
+    ("continue", "Continue to Site"),
+    ("complete", "Complete Verification"),
+    ("solving", "Solving Challenge..."),
+    ("verifying", "Verifying..."),
+    ("failed", "Verification Failed - Try Again"),
+    ("incomplete", "Please complete the verification first."),
+    ("verify_failed", "Verification failed. Please try again."),
+    ("error", "Error occurred. Please try again."),
+    ("protects", "This verification helps protect"),
+    (
+        "powered_by",
+        "Powered by ALTCHA - Privacy-friendly proof of work",
+    ),
+];
+
+/// Per-request template variables. Together with the site's own strings
+/// this is everything a challenge page template can refer to:
+///
+/// - `site.name`, `site.title`, `site.description`, `site.host`
+/// - `host`: the Host the visitor asked for
+/// - `return_to` (already validated) and `return_sig`, its signature
+/// - `challenge_url`, `verify_url`
+/// - `error`: set when a form submission failed
+/// - `widget_url` / `widget_integrity` (unset if the widget is not vendored)
+///   and `script_url` / `script_integrity` for the page script
+/// - `lang` and `strings.<key>` (see [`DEFAULT_STRINGS`])
+#[derive(Debug, Serialize)]
+pub struct PageContext<'a> {
+    pub host: &'a str,
+    pub return_to: &'a str,
+    pub return_sig: &'a str,
+    pub challenge_url: &'a str,
+    pub verify_url: &'a str,
+    pub widget_url: Option<&'a str>,
+    pub widget_integrity: Option<&'a str>,
+    pub script_url: &'a str,
+    pub script_integrity: &'a str,
+    pub error: Option<&'a str>,
+}
+
+/// Challenge page templates: the compiled-in default plus any per-site
+/// overrides read from disk.
+pub struct PageTemplates {
+    env: Environment<'static>,
+    /// Template file for each site host that has one
+    files: HashMap<String, PathBuf>,
+    /// Re-read template files on every render (dev mode)
+    hot_reload: bool,
+}
+
+impl PageTemplates {
+    /// Loads and compiles every site template so mistakes surface at startup.
+    pub fn load(sites: &SiteRegistry, hot_reload: bool) -> Result<Self, ConfigError> {
+        let mut env = environment();
+        env.add_template(DEFAULT_TEMPLATE_NAME, DEFAULT_TEMPLATE)
+            .map_err(|e| ConfigError::Invalid(format!("built-in template: {}", e)))?;
+
+        let mut files = HashMap::new();
+        for site in sites.sites() {
+            if let Some(path) = &site.template {
+                env.add_template_owned(site.host.clone(), read_template(path)?)
+                    .map_err(|e| {
+                        ConfigError::Invalid(format!("template {}: {}", path.display(), e))
+                    })?;
+                files.insert(site.host.clone(), path.clone());
+            }
+        }
+
+        Ok(Self {
+            env,
+            files,
+            hot_reload,
+        })
+    }
+
+    pub fn render(&self, site: &Site, page: &PageContext) -> Result<String, minijinja::Error> {
+        let mut strings: BTreeMap<&str, &str> = DEFAULT_STRINGS.iter().copied().collect();
+        strings.extend(
+            site.strings
+                .iter()
+                .map(|(key, value)| (key.as_str(), value.as_str())),
+        );
+
+        let ctx = context! {
+            site => context! {
+                name => &site.service_name,
+                title => &site.title,
+                description => &site.description,
+                host => &site.host,
+            },
+            lang => &site.lang,
+            strings => strings,
+            ..minijinja::Value::from_serialize(page)
+        };
+
+        match self.files.get(&site.host) {
+            Some(path) if self.hot_reload => {
+                let source = fs::read_to_string(path).map_err(|e| {
+                    minijinja::Error::new(
+                        ErrorKind::TemplateNotFound,
+                        format!("cannot read {}", path.display()),
+                    )
+                    .with_source(e)
+                })?;
+                environment().render_str(&source, ctx)
+            }
+            Some(_) => self.env.get_template(&site.host)?.render(ctx),
+            None => self.env.get_template(DEFAULT_TEMPLATE_NAME)?.render(ctx),
+        }
+    }
+}
+
+fn environment() -> Environment<'static> {
+    let mut env = Environment::new();
+    // Site templates are named after their host, not *.html, so turn HTML
+    // escaping on explicitly
+    env.set_auto_escape_callback(|_| AutoEscape::Html);
+    env
+}
+
+fn read_template(path: &Path) -> Result<String, ConfigError> {
+    fs::read_to_string(path).map_err(|source| ConfigError::Io {
+        path: path.to_path_buf(),
+        source,
+    })
+}
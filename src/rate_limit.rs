@@ -0,0 +1,407 @@
+use axum::{
+    extract::Request,
+    response::{IntoResponse, Response},
+};
+use lru::LruCache;
+use serde::Deserialize;
+use std::future::Future;
+use std::num::NonZeroUsize;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, RwLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tower::{Layer, Service};
+use tracing::warn;
+
+use crate::client_ip::subnet_key;
+use crate::error::AppError;
+use crate::redact;
+
+/// A token bucket: `burst` requests at once, refilled at `per_minute`.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Limit {
+    pub per_minute: u32,
+    pub burst: u32,
+}
+
+/// Limits for one endpoint; an unset limit does not apply.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EndpointLimits {
+    /// Per client IP
+    pub ip: Option<Limit>,
+    /// Per client subnet (IPv4 /24, IPv6 /64)
+    pub subnet: Option<Limit>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    pub challenge: EndpointLimits,
+    pub verify: EndpointLimits,
+    /// Maximum number of buckets kept; the least recently used is dropped
+    /// first
+    pub max_tracked: usize,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            challenge: EndpointLimits {
+                ip: Some(Limit {
+                    per_minute: 60,
+                    burst: 20,
+                }),
+                subnet: Some(Limit {
+                    per_minute: 300,
+                    burst: 100,
+                }),
+            },
+            verify: EndpointLimits {
+                ip: Some(Limit {
+                    per_minute: 30,
+                    burst: 10,
+                }),
+                subnet: Some(Limit {
+                    per_minute: 150,
+                    burst: 50,
+                }),
+            },
+            max_tracked: 100_000,
+        }
+    }
+}
+
+impl RateLimitConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_tracked == 0 {
+            return Err("rate_limit.max_tracked must be greater than 0".to_string());
+        }
+        let limits = [
+            ("challenge.ip", self.challenge.ip),
+            ("challenge.subnet", self.challenge.subnet),
+            ("verify.ip", self.verify.ip),
+            ("verify.subnet", self.verify.subnet),
+        ];
+        for (name, limit) in limits {
+            if let Some(limit) = limit {
+                if limit.per_minute == 0 || limit.burst == 0 {
+                    return Err(format!(
+                        "rate_limit.{} per_minute and burst must be at least 1",
+                        name
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn limits(&self, endpoint: Endpoint) -> EndpointLimits {
+        match endpoint {
+            Endpoint::Challenge => self.challenge,
+            Endpoint::Verify => self.verify,
+        }
+    }
+}
+
+/// The rate-limited endpoints, each with its own buckets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Endpoint {
+    Challenge,
+    Verify,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Scope {
+    Ip,
+    Subnet,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn full(limit: Limit, now: Instant) -> Self {
+        Self {
+            tokens: f64::from(limit.burst),
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, limit: Limit, now: Instant) {
+        let per_sec = f64::from(limit.per_minute) / 60.0;
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * per_sec).min(f64::from(limit.burst));
+        self.updated = now;
+    }
+
+    /// How long until a whole token is available.
+    fn wait(&self, limit: Limit) -> Duration {
+        let per_sec = f64::from(limit.per_minute) / 60.0;
+        Duration::from_secs_f64(((1.0 - self.tokens) / per_sec).max(0.0))
+    }
+}
+
+type BucketKey = (Endpoint, Scope, String);
+
+/// Token buckets per client IP and per subnet, in a bounded LRU.
+pub struct RateLimiter {
+    config: RwLock<Arc<RateLimitConfig>>,
+    buckets: Mutex<LruCache<BucketKey, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        let capacity = capacity(&config);
+        Self {
+            config: RwLock::new(Arc::new(config)),
+            buckets: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+
+    /// Swaps in new limits. Existing buckets carry over.
+    pub fn reconfigure(&self, config: RateLimitConfig) {
+        self.buckets
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .resize(capacity(&config));
+        *self.config.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(config);
+    }
+
+    fn config(&self) -> Arc<RateLimitConfig> {
+        self.config
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Takes a token for one request from `client_ip`, or says how long to
+    /// wait. A request only counts against its buckets if all of them let
+    /// it through.
+    pub fn check(&self, endpoint: Endpoint, client_ip: &str) -> Result<(), Duration> {
+        self.check_at(endpoint, client_ip, Instant::now())
+    }
+
+    fn check_at(&self, endpoint: Endpoint, client_ip: &str, now: Instant) -> Result<(), Duration> {
+        let config = self.config();
+        if !config.enabled {
+            return Ok(());
+        }
+        let limits = config.limits(endpoint);
+        let scopes = [
+            (Scope::Ip, limits.ip, client_ip.to_string()),
+            (Scope::Subnet, limits.subnet, subnet_key(client_ip)),
+        ];
+
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        let mut wait = Duration::ZERO;
+        let mut granted = Vec::with_capacity(scopes.len());
+        for (scope, limit, key) in scopes {
+            let Some(limit) = limit else {
+                continue;
+            };
+            let bucket = buckets
+                .get_or_insert_mut((endpoint, scope, key.clone()), || Bucket::full(limit, now));
+            bucket.refill(limit, now);
+            if bucket.tokens < 1.0 {
+                wait = wait.max(bucket.wait(limit));
+            }
+            granted.push((endpoint, scope, key));
+        }
+        if !wait.is_zero() {
+            return Err(wait);
+        }
+        for key in granted {
+            if let Some(bucket) = buckets.get_mut(&key) {
+                bucket.tokens -= 1.0;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn capacity(config: &RateLimitConfig) -> NonZeroUsize {
+    NonZeroUsize::new(config.max_tracked).unwrap_or(NonZeroUsize::MIN)
+}
+
+/// Resolves the client IP a request is counted against.
+pub type ClientIpFn = Arc<dyn Fn(&Request) -> Option<String> + Send + Sync>;
+
+/// Applies a [`RateLimiter`] to one endpoint. Throttled requests get
+/// `429 Too Many Requests` with `Retry-After` and never reach the handler.
+#[derive(Clone)]
+pub struct RateLimitLayer {
+    limiter: Arc<RateLimiter>,
+    endpoint: Endpoint,
+    client_ip: ClientIpFn,
+}
+
+impl RateLimitLayer {
+    pub fn new(limiter: Arc<RateLimiter>, endpoint: Endpoint, client_ip: ClientIpFn) -> Self {
+        Self {
+            limiter,
+            endpoint,
+            client_ip,
+        }
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimit {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimit<S> {
+    inner: S,
+    layer: RateLimitLayer,
+}
+
+impl<S> Service<Request> for RateLimit<S>
+where
+    S: Service<Request, Response = Response> + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        if let Some(client_ip) = (self.layer.client_ip)(&request) {
+            if let Err(wait) = self.layer.limiter.check(self.layer.endpoint, &client_ip) {
+                warn!(
+                    endpoint = ?self.layer.endpoint,
+                    ip = %redact::ip(&client_ip),
+                    retry_secs = wait.as_secs_f64(),
+                    "Rate limited"
+                );
+                // Retry-After is in whole seconds; round up so the client
+                // does not come back too early
+                let retry_after = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+                let response = AppError::RateLimited(retry_after).into_response();
+                return Box::pin(async move { Ok(response) });
+            }
+        }
+        Box::pin(self.inner.call(request))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::{header, StatusCode};
+    use std::convert::Infallible;
+    use tower::ServiceExt;
+
+    const CLIENT_IP: &str = "192.0.2.10";
+    const NEIGHBOUR_IP: &str = "192.0.2.11";
+
+    fn limiter(ip: Option<Limit>, subnet: Option<Limit>) -> RateLimiter {
+        RateLimiter::new(RateLimitConfig {
+            challenge: EndpointLimits { ip, subnet },
+            ..RateLimitConfig::default()
+        })
+    }
+
+    #[test]
+    fn a_burst_is_exhausted_then_refilled() {
+        let limit = Limit {
+            per_minute: 60,
+            burst: 3,
+        };
+        let limiter = limiter(Some(limit), None);
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert_eq!(
+                limiter.check_at(Endpoint::Challenge, CLIENT_IP, start),
+                Ok(())
+            );
+        }
+        let wait = limiter
+            .check_at(Endpoint::Challenge, CLIENT_IP, start)
+            .unwrap_err();
+        assert_eq!(wait, Duration::from_secs(1));
+
+        // One token a second; other clients have their own bucket
+        let later = start + Duration::from_secs(1);
+        assert_eq!(
+            limiter.check_at(Endpoint::Challenge, CLIENT_IP, later),
+            Ok(())
+        );
+        assert!(limiter
+            .check_at(Endpoint::Challenge, CLIENT_IP, later)
+            .is_err());
+        assert_eq!(
+            limiter.check_at(Endpoint::Challenge, "198.51.100.7", later),
+            Ok(())
+        );
+        assert_eq!(limiter.check_at(Endpoint::Verify, CLIENT_IP, later), Ok(()));
+    }
+
+    #[test]
+    fn a_subnet_denial_does_not_spend_an_ip_token() {
+        let limiter = limiter(
+            Some(Limit {
+                per_minute: 60,
+                burst: 5,
+            }),
+            Some(Limit {
+                per_minute: 60,
+                burst: 1,
+            }),
+        );
+        let now = Instant::now();
+        assert_eq!(
+            limiter.check_at(Endpoint::Challenge, CLIENT_IP, now),
+            Ok(())
+        );
+        assert!(limiter
+            .check_at(Endpoint::Challenge, NEIGHBOUR_IP, now)
+            .is_err());
+
+        let mut buckets = limiter.buckets.lock().unwrap();
+        let key = (Endpoint::Challenge, Scope::Ip, NEIGHBOUR_IP.to_string());
+        assert_eq!(buckets.get(&key).unwrap().tokens, 5.0);
+    }
+
+    #[tokio::test]
+    async fn throttled_requests_get_retry_after_rounded_up() {
+        let limiter = Arc::new(limiter(
+            Some(Limit {
+                per_minute: 1,
+                burst: 1,
+            }),
+            None,
+        ));
+        let client_ip: ClientIpFn = Arc::new(|_| Some(CLIENT_IP.to_string()));
+        let service = RateLimitLayer::new(limiter, Endpoint::Challenge, client_ip).layer(
+            tower::service_fn(|_| async { Ok::<_, Infallible>(StatusCode::OK.into_response()) }),
+        );
+        let request = || Request::new(axum::body::Body::empty());
+
+        let response = service.clone().oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Just under a minute to wait, so a whole 60 seconds
+        let response = service.oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "60");
+    }
+}
@@ -0,0 +1,300 @@
+use rand::Rng;
+use serde::Deserialize;
+use std::fmt;
+use std::sync::{Arc, OnceLock, RwLock};
+
+use crate::client_ip::subnet_key;
+use crate::crypto::hmac_hex;
+
+/// How a sensitive value appears in logs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RedactMode {
+    /// As is
+    Full,
+    /// IPs by subnet (/24 or /64), anything else by its first few
+    /// characters
+    Truncated,
+    /// A keyed hash: the same value always gets the same pseudonym, so log
+    /// lines can still be correlated
+    Pseudonym,
+    Omitted,
+}
+
+/// Redaction per kind of value, from `[logging.redact]`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RedactConfig {
+    /// Client IPs, and token subjects derived from them
+    pub ip: RedactMode,
+    /// Verification tokens
+    pub token: RedactMode,
+    /// Challenge salts, hashes and signatures
+    pub salt: RedactMode,
+    /// Submitted numbers, the answers to challenges
+    pub solution: RedactMode,
+}
+
+impl Default for RedactConfig {
+    fn default() -> Self {
+        Self {
+            ip: RedactMode::Pseudonym,
+            token: RedactMode::Omitted,
+            salt: RedactMode::Truncated,
+            solution: RedactMode::Omitted,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Ip,
+    Token,
+    Salt,
+    Solution,
+}
+
+impl Kind {
+    fn as_str(self) -> &'static str {
+        match self {
+            Kind::Ip => "ip",
+            Kind::Token => "token",
+            Kind::Salt => "salt",
+            Kind::Solution => "solution",
+        }
+    }
+}
+
+/// Applies a [`RedactConfig`] to values on their way into a log line.
+pub struct Redactor {
+    config: RedactConfig,
+    key: Vec<u8>,
+}
+
+impl Redactor {
+    /// `key` keys the pseudonyms, see [`crate::keyring::Key::pseudonym_key`].
+    pub fn new(config: RedactConfig, key: &[u8]) -> Self {
+        Self {
+            config,
+            key: key.to_vec(),
+        }
+    }
+
+    fn mode(&self, kind: Kind) -> RedactMode {
+        match kind {
+            Kind::Ip => self.config.ip,
+            Kind::Token => self.config.token,
+            Kind::Salt => self.config.salt,
+            Kind::Solution => self.config.solution,
+        }
+    }
+
+    pub fn apply(&self, kind: Kind, value: &str) -> String {
+        match self.mode(kind) {
+            RedactMode::Full => value.to_string(),
+            RedactMode::Truncated if kind == Kind::Ip => subnet_key(value),
+            RedactMode::Truncated => truncate(value),
+            RedactMode::Pseudonym => {
+                // The kind is hashed in too, so equal values of different
+                // kinds cannot be matched up across log lines
+                let data = format!("{}\0{}", kind.as_str(), value);
+                let mac = hmac_hex(&self.key, data.as_bytes());
+                format!("~{}", &mac[..16])
+            }
+            RedactMode::Omitted => "[redacted]".to_string(),
+        }
+    }
+}
+
+// At most half of the value and never more than 8 characters, so short
+// values are not given away whole
+fn truncate(value: &str) -> String {
+    let keep = (value.chars().count() / 2).min(8);
+    let prefix: String = value.chars().take(keep).collect();
+    format!("{}…", prefix)
+}
+
+fn global() -> &'static RwLock<Arc<Redactor>> {
+    static REDACTOR: OnceLock<RwLock<Arc<Redactor>>> = OnceLock::new();
+    REDACTOR.get_or_init(|| {
+        // Until one is installed: default modes under a throwaway key
+        let key: [u8; 32] = rand::thread_rng().gen();
+        RwLock::new(Arc::new(Redactor::new(RedactConfig::default(), &key)))
+    })
+}
+
+/// Makes `redactor` the one every log line goes through. Called at startup
+/// and on reload.
+pub fn install(redactor: Redactor) {
+    *global().write().unwrap_or_else(|e| e.into_inner()) = Arc::new(redactor);
+}
+
+fn current() -> Arc<Redactor> {
+    global().read().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// A value that is redacted when it is formatted, for use as a log field:
+/// `warn!(ip = %redact::ip(&client_ip), "...")`.
+pub struct Redacted<'a> {
+    kind: Kind,
+    value: &'a str,
+}
+
+impl fmt::Display for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&current().apply(self.kind, self.value))
+    }
+}
+
+pub fn ip(value: &str) -> Redacted<'_> {
+    Redacted {
+        kind: Kind::Ip,
+        value,
+    }
+}
+
+pub fn token(value: &str) -> Redacted<'_> {
+    Redacted {
+        kind: Kind::Token,
+        value,
+    }
+}
+
+pub fn salt(value: &str) -> Redacted<'_> {
+    Redacted {
+        kind: Kind::Salt,
+        value,
+    }
+}
+
+pub fn solution(value: &str) -> Redacted<'_> {
+    Redacted {
+        kind: Kind::Solution,
+        value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::binding::BindingPolicy;
+    use crate::challenge::{verify_solution, AltchaPayload};
+    use crate::test_support::{key, solved};
+    use crate::token::{create_verification_token, verify_token, TokenCodec};
+    use std::io;
+    use std::sync::Mutex;
+
+    const SALT: &str =
+        "00112233445566778899aabbccddeeff?expires=18446744073709551615&maxnumber=999999";
+    const NUMBER: u32 = 731_946;
+    const CLIENT_IP: &str = "203.0.113.77";
+    const OTHER_IP: &str = "198.51.100.23";
+
+    struct Capture(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Everything logged while `f` runs, under the default redaction
+    fn captured_logs(f: impl FnOnce()) -> String {
+        let buffer = Arc::new(Mutex::new(Vec::new()));
+        let writer = buffer.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer(move || Capture(writer.clone()))
+            .finish();
+        tracing::subscriber::with_default(subscriber, f);
+        let logs = buffer.lock().unwrap().clone();
+        String::from_utf8(logs).unwrap()
+    }
+
+    // The challenge for `SALT` and `NUMBER`, answered with `number`
+    fn payload(number: u32) -> AltchaPayload {
+        AltchaPayload {
+            number,
+            ..solved(SALT, NUMBER)
+        }
+    }
+
+    #[test]
+    fn modes_apply_per_kind() {
+        let redactor = Redactor::new(
+            RedactConfig {
+                ip: RedactMode::Truncated,
+                token: RedactMode::Full,
+                salt: RedactMode::Truncated,
+                solution: RedactMode::Omitted,
+            },
+            b"key",
+        );
+        assert_eq!(redactor.apply(Kind::Ip, CLIENT_IP), "203.0.113.0/24");
+        assert_eq!(redactor.apply(Kind::Ip, "2001:db8::1"), "2001:db8:0:0::/64");
+        assert_eq!(redactor.apply(Kind::Token, "v1.abc"), "v1.abc");
+        assert_eq!(redactor.apply(Kind::Salt, SALT), "00112233…");
+        assert_eq!(redactor.apply(Kind::Salt, "abcd"), "ab…");
+        assert_eq!(redactor.apply(Kind::Solution, "731946"), "[redacted]");
+    }
+
+    #[test]
+    fn pseudonyms_are_stable_and_keyed() {
+        let config = RedactConfig {
+            ip: RedactMode::Pseudonym,
+            ..RedactConfig::default()
+        };
+        let redactor = Redactor::new(config.clone(), b"key");
+        let pseudonym = redactor.apply(Kind::Ip, CLIENT_IP);
+        assert_eq!(pseudonym.len(), 17);
+        assert!(!pseudonym.contains(CLIENT_IP));
+        assert_eq!(redactor.apply(Kind::Ip, CLIENT_IP), pseudonym);
+        assert_ne!(redactor.apply(Kind::Ip, OTHER_IP), pseudonym);
+        assert_ne!(
+            Redactor::new(config, b"other key").apply(Kind::Ip, CLIENT_IP),
+            pseudonym
+        );
+    }
+
+    #[test]
+    fn solutions_and_salts_stay_out_of_logs() {
+        let keys = [key()];
+        let logs = captured_logs(|| {
+            assert_eq!(verify_solution(&payload(NUMBER), &keys), Ok(()));
+            assert!(verify_solution(&payload(NUMBER + 1), &keys).is_err());
+        });
+        assert!(logs.contains("Verifying solution"), "{}", logs);
+        assert!(logs.contains("Proof of work failed"), "{}", logs);
+        assert!(!logs.contains(&NUMBER.to_string()), "{}", logs);
+        assert!(!logs.contains(&(NUMBER + 1).to_string()), "{}", logs);
+        assert!(!logs.contains(SALT), "{}", logs);
+    }
+
+    #[test]
+    fn tokens_and_ips_stay_out_of_logs() {
+        let key = key();
+        let codec = TokenCodec::new(None);
+        let token = create_verification_token(
+            &codec,
+            BindingPolicy::Exact,
+            CLIENT_IP,
+            "",
+            "example.org",
+            &key,
+            3600,
+        )
+        .unwrap();
+        let logs = captured_logs(|| {
+            assert!(verify_token(&codec, &token, OTHER_IP, "", "example.org", &[key]).is_err());
+        });
+        assert!(logs.contains("Token binding mismatch"), "{}", logs);
+        assert!(!logs.contains(CLIENT_IP), "{}", logs);
+        assert!(!logs.contains(OTHER_IP), "{}", logs);
+        assert!(!logs.contains(&token), "{}", logs);
+    }
+}
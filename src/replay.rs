@@ -0,0 +1,244 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use crate::unix_now;
+
+/// Remembers challenges that have already been redeemed so a single solved
+/// payload cannot be exchanged for more than one verification cookie.
+///
+/// Implementations must be safe to share between handlers. Other backends
+/// (Redis, memcached, ...) can be plugged in by implementing this trait.
+pub trait ReplayStore: Send + Sync {
+    /// Marks `key` as spent until `expires_at` (unix seconds).
+    ///
+    /// Returns `true` if the key was fresh and is now recorded, `false` if it
+    /// had already been spent and has not yet expired.
+    fn mark_spent(&self, key: &str, expires_at: u64) -> bool;
+
+    /// Whether the store is reachable and can keep what it holds, for
+    /// `/readyz`.
+    fn health(&self) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Writes anything held in memory to durable storage. Called
+    /// periodically and on shutdown; stores that write through need not do
+    /// anything.
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Builds the replay key for a solved challenge. The signature is unique per
+/// issued challenge; the salt is included so keys stay distinct even if two
+/// challenges were ever signed identically.
+pub fn replay_key(salt: &str, signature: &str) -> String {
+    format!("{}:{}", salt, signature)
+}
+
+/// Process-local replay store. Spent keys are dropped once they expire.
+/// With a `path`, unexpired keys are saved there on [`ReplayStore::flush`]
+/// and loaded back at startup, so a restart does not reopen replays.
+pub struct MemoryReplayStore {
+    inner: Mutex<MemoryInner>,
+    sweep_interval: u64,
+    path: Option<PathBuf>,
+}
+
+struct MemoryInner {
+    spent: HashMap<String, u64>,
+    next_sweep: u64,
+    /// Something was spent since the last flush
+    dirty: bool,
+    /// Why the last flush failed, until one succeeds
+    flush_error: Option<String>,
+}
+
+impl MemoryReplayStore {
+    pub fn new() -> Self {
+        Self {
+            inner: Mutex::new(MemoryInner {
+                spent: HashMap::new(),
+                next_sweep: 0,
+                dirty: false,
+                flush_error: None,
+            }),
+            sweep_interval: 60,
+            path: None,
+        }
+    }
+
+    /// A store saved to `path`, starting from whatever was saved there
+    /// before. A missing file is an empty store. The store is saved once
+    /// straight away, so an unwritable `path` fails here rather than at the
+    /// first flush.
+    pub fn persistent(path: PathBuf) -> io::Result<Self> {
+        let mut spent: HashMap<String, u64> = match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        let now = unix_now();
+        spent.retain(|_, expires| *expires > now);
+
+        let store = Self {
+            path: Some(path),
+            ..Self::new()
+        };
+        {
+            let mut inner = store.lock();
+            inner.spent = spent;
+            inner.dirty = true;
+        }
+        store.flush()?;
+        Ok(store)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, MemoryInner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Default for MemoryReplayStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReplayStore for MemoryReplayStore {
+    fn mark_spent(&self, key: &str, expires_at: u64) -> bool {
+        let now = unix_now();
+        let mut inner = self.lock();
+
+        // Drop expired entries periodically so memory stays bounded by the
+        // number of challenges redeemed within their lifetime.
+        if now >= inner.next_sweep {
+            inner.spent.retain(|_, expires| *expires > now);
+            inner.next_sweep = now + self.sweep_interval;
+        }
+
+        match inner.spent.get(key) {
+            Some(&expires) if expires > now => false,
+            _ => {
+                inner.spent.insert(key.to_string(), expires_at);
+                inner.dirty = true;
+                true
+            }
+        }
+    }
+
+    // Spends that cannot be saved would be lost on a crash or restart
+    fn health(&self) -> Result<(), String> {
+        match &self.lock().flush_error {
+            Some(e) => Err(e.clone()),
+            None => Ok(()),
+        }
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let now = unix_now();
+        let json = {
+            let mut inner = self.lock();
+            if !inner.dirty {
+                return Ok(());
+            }
+            let live: HashMap<&String, &u64> = inner
+                .spent
+                .iter()
+                .filter(|(_, expires)| **expires > now)
+                .collect();
+            let json = serde_json::to_vec(&live)?;
+            inner.dirty = false;
+            json
+        };
+
+        // Write then rename, so a crash mid-write leaves the old file intact
+        let tmp = path.with_extension("tmp");
+        let result = fs::write(&tmp, json).and_then(|()| fs::rename(&tmp, path));
+        let mut inner = self.lock();
+        match &result {
+            Ok(()) => inner.flush_error = None,
+            Err(e) => {
+                inner.dirty = true;
+                inner.flush_error = Some(format!("cannot save to {}: {}", path.display(), e));
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("altcha-replay-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("replay.json")
+    }
+
+    #[test]
+    fn a_key_is_spent_once_until_it_expires() {
+        let store = MemoryReplayStore::new();
+        let expires = unix_now() + 300;
+        assert!(store.mark_spent("salt:sig", expires));
+        assert!(!store.mark_spent("salt:sig", expires));
+        assert!(store.mark_spent("salt:other", expires));
+
+        // An expired entry no longer blocks the key
+        store
+            .lock()
+            .spent
+            .insert("salt:old".to_string(), unix_now());
+        assert!(store.mark_spent("salt:old", expires));
+    }
+
+    #[test]
+    fn the_sweep_drops_expired_entries() {
+        let store = MemoryReplayStore {
+            sweep_interval: 0,
+            ..MemoryReplayStore::new()
+        };
+        let now = unix_now();
+        store.lock().spent.insert("salt:old".to_string(), now);
+        assert!(store.mark_spent("salt:new", now + 300));
+
+        let inner = store.lock();
+        assert!(!inner.spent.contains_key("salt:old"));
+        assert!(inner.spent.contains_key("salt:new"));
+    }
+
+    #[test]
+    fn spent_keys_survive_a_flush_and_reload() {
+        let path = temp_path("reload");
+        let _ = fs::remove_file(&path);
+        let now = unix_now();
+
+        let store = MemoryReplayStore::persistent(path.clone()).unwrap();
+        assert!(store.mark_spent("salt:live", now + 300));
+        store.lock().spent.insert("salt:old".to_string(), now);
+        store.flush().unwrap();
+
+        let reloaded = MemoryReplayStore::persistent(path.clone()).unwrap();
+        assert!(!reloaded.lock().spent.contains_key("salt:old"));
+        assert!(!reloaded.mark_spent("salt:live", now + 300));
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn an_unwritable_path_fails_at_startup() {
+        let path = temp_path("unwritable");
+        let missing = path.with_file_name("missing").join("replay.json");
+        assert!(MemoryReplayStore::persistent(missing).is_err());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,236 @@
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+
+use crate::binding::BindingPolicy;
+use crate::challenge::Algorithm;
+use crate::config::Config;
+use crate::page::DEFAULT_STRINGS;
+
+/// One protected site as written in `[[sites]]`. Settings left unset fall
+/// back to the global `[challenge]`, `[cookie]` and `[binding]` values.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SiteConfig {
+    /// Hostname, or `*.example.org` for any subdomain of example.org
+    pub host: String,
+    /// Name shown on the challenge page
+    pub service_name: String,
+    /// Page title; defaults to "Verification Required - <service_name>"
+    pub title: Option<String>,
+    /// What the challenge page says is being protected; defaults to
+    /// `service_name`
+    pub description: Option<String>,
+    /// Base difficulty, before the difficulty engine scales it
+    pub max_number: Option<u32>,
+    pub algorithm: Option<Algorithm>,
+    /// Lifetime of the verification token and its cookie, in seconds
+    pub token_ttl_secs: Option<u64>,
+    pub cookie_name: Option<String>,
+    pub binding: Option<BindingPolicy>,
+    /// Challenge page template replacing the compiled-in default
+    pub template: Option<PathBuf>,
+    /// Page language; defaults to "en"
+    pub lang: Option<String>,
+    /// Overrides for individual page strings (see `page::DEFAULT_STRINGS`)
+    #[serde(default)]
+    pub strings: BTreeMap<String, String>,
+    /// Other hosts (or `*.domain` wildcards) that `return_to` may point at;
+    /// the requested host itself is always allowed
+    #[serde(default)]
+    pub return_hosts: Vec<String>,
+}
+
+impl SiteConfig {
+    fn new(host: &str, service_name: &str, description: &str) -> Self {
+        Self {
+            host: host.to_string(),
+            service_name: service_name.to_string(),
+            title: None,
+            description: Some(description.to_string()),
+            max_number: None,
+            algorithm: None,
+            token_ttl_secs: None,
+            cookie_name: None,
+            binding: None,
+            template: None,
+            lang: None,
+            strings: BTreeMap::new(),
+            return_hosts: Vec::new(),
+        }
+    }
+}
+
+/// The sites served when the configuration does not list any.
+pub fn default_sites() -> Vec<SiteConfig> {
+    vec![
+        SiteConfig {
+            title: Some("Verification Required - Princeton OAR".to_string()),
+            ..SiteConfig::new(
+                "oar.princeton.edu",
+                "Princeton OAR (Open Access Repository)",
+                "Princeton OAR repository",
+            )
+        },
+        SiteConfig::new(
+            "dataspace.princeton.edu",
+            "Princeton DataSpace",
+            "Princeton DataSpace repository",
+        ),
+    ]
+}
+
+/// A site with every setting resolved against the global defaults.
+#[derive(Debug, Clone)]
+pub struct Site {
+    pub host: String,
+    pub title: String,
+    pub service_name: String,
+    pub description: String,
+    pub max_number: u32,
+    pub algorithm: Algorithm,
+    pub token_ttl_secs: u64,
+    pub cookie_name: String,
+    pub binding: BindingPolicy,
+    pub template: Option<PathBuf>,
+    pub lang: String,
+    pub strings: BTreeMap<String, String>,
+    pub return_hosts: Vec<String>,
+}
+
+impl Site {
+    fn resolve(site: &SiteConfig, config: &Config) -> Self {
+        Self {
+            host: site.host.to_ascii_lowercase(),
+            title: site
+                .title
+                .clone()
+                .unwrap_or_else(|| format!("Verification Required - {}", site.service_name)),
+            service_name: site.service_name.clone(),
+            description: site
+                .description
+                .clone()
+                .unwrap_or_else(|| site.service_name.clone()),
+            max_number: site.max_number.unwrap_or(config.challenge.max_number),
+            algorithm: site.algorithm.unwrap_or(config.challenge.algorithm),
+            token_ttl_secs: site.token_ttl_secs.unwrap_or(config.cookie.ttl_secs),
+            cookie_name: site
+                .cookie_name
+                .clone()
+                .unwrap_or_else(|| config.cookie.name.clone()),
+            binding: site.binding.unwrap_or(config.binding.default),
+            template: site.template.clone(),
+            lang: site.lang.clone().unwrap_or_else(|| "en".to_string()),
+            strings: site.strings.clone(),
+            return_hosts: site
+                .return_hosts
+                .iter()
+                .map(|host| host.to_ascii_lowercase())
+                .collect(),
+        }
+    }
+
+    /// Page string `key`, from the site's `strings` or the built-in default.
+    pub fn string<'a>(&'a self, key: &'a str) -> &'a str {
+        self.strings.get(key).map_or_else(
+            || {
+                DEFAULT_STRINGS
+                    .iter()
+                    .find(|(name, _)| *name == key)
+                    .map_or(key, |(_, text)| text)
+            },
+            String::as_str,
+        )
+    }
+}
+
+/// Every site this server protects, looked up by Host.
+#[derive(Debug, Default)]
+pub struct SiteRegistry {
+    exact: HashMap<String, Site>,
+    /// `*.example.org` sites, most specific first
+    wildcards: Vec<Site>,
+}
+
+impl SiteRegistry {
+    /// Builds the registry from an already validated configuration.
+    pub fn from_config(config: &Config) -> Self {
+        let mut registry = Self::default();
+        for site in &config.sites {
+            let site = Site::resolve(site, config);
+            if site.host.starts_with("*.") {
+                registry.wildcards.push(site);
+            } else {
+                registry.exact.insert(site.host.clone(), site);
+            }
+        }
+        registry
+            .wildcards
+            .sort_by_key(|site| std::cmp::Reverse(site.host.len()));
+        registry
+    }
+
+    /// The site for `host` (without port). An exact entry wins over a
+    /// wildcard, and a longer wildcard over a shorter one.
+    pub fn lookup(&self, host: &str) -> Option<&Site> {
+        let host = host.to_ascii_lowercase();
+        self.exact.get(&host).or_else(|| {
+            self.wildcards
+                .iter()
+                .find(|site| host_matches(&site.host, &host))
+        })
+    }
+
+    pub fn sites(&self) -> impl Iterator<Item = &Site> {
+        self.exact.values().chain(self.wildcards.iter())
+    }
+
+    pub fn hosts(&self) -> Vec<&str> {
+        let mut hosts: Vec<&str> = self.sites().map(|site| site.host.as_str()).collect();
+        hosts.sort_unstable();
+        hosts
+    }
+}
+
+/// Whether `host` is `pattern`, or a subdomain of `example.org` when
+/// `pattern` is `*.example.org`. `pattern` must already be lowercase.
+pub fn host_matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix('*') {
+        Some(suffix) => host.len() > suffix.len() && host.to_ascii_lowercase().ends_with(suffix),
+        None => pattern.eq_ignore_ascii_case(host),
+    }
+}
+
+/// `example.org` or `*.example.org`: dot-separated labels of letters,
+/// digits and hyphens.
+pub fn is_site_host(host: &str) -> bool {
+    is_hostname(host.strip_prefix("*.").unwrap_or(host))
+}
+
+/// The name part of a Host header, `name` or `name:port`, if the name is a
+/// hostname and the port is a number.
+pub fn host_header_name(host: &str) -> Option<&str> {
+    let name = match host.split_once(':') {
+        Some((name, port))
+            if port.bytes().all(|b| b.is_ascii_digit()) && port.parse::<u16>().is_ok() =>
+        {
+            name
+        }
+        Some(_) => return None,
+        None => host,
+    };
+    is_hostname(name).then_some(name)
+}
+
+/// A DNS name (or dotted IPv4 address): dot-separated labels of letters,
+/// digits and hyphens.
+pub fn is_hostname(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 253
+        && name.split('.').all(|label| {
+            (1..=63).contains(&label.len())
+                && label
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        })
+}
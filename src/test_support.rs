@@ -0,0 +1,24 @@
+//! Fixtures shared by the unit tests.
+
+use crate::challenge::{sign_challenge, Algorithm, AltchaPayload};
+use crate::keyring::Key;
+
+/// Master secret of [`key`], also the input of the key derivation vectors.
+pub const SECRET: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+pub fn key() -> Key {
+    Key::new("test", SECRET)
+}
+
+/// A SHA-256 challenge for `salt` solved by `number` and signed by [`key`].
+pub fn solved(salt: &str, number: u32) -> AltchaPayload {
+    let algorithm = Algorithm::Sha256;
+    let challenge = algorithm.hash_hex(format!("{}{}", salt, number).as_bytes());
+    AltchaPayload {
+        algorithm: algorithm.to_string(),
+        signature: sign_challenge(algorithm, &challenge, salt, key().challenge_key()),
+        challenge,
+        number,
+        salt: salt.to_string(),
+    }
+}
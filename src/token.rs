@@ -0,0 +1,414 @@
+use base64::{engine::general_purpose, Engine as _};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use tracing::warn;
+
+use crate::binding::BindingPolicy;
+use crate::crypto::{hmac_hex, verify_hmac_hex, MacError};
+use crate::keyring::Key;
+use crate::redact;
+use crate::unix_now;
+
+/// What a verification token asserts about the visitor.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Claims {
+    /// How `subject` ties the token to the client; tokens that predate
+    /// binding policies are exact-IP bound
+    #[serde(rename = "bnd", default)]
+    pub binding: BindingPolicy,
+    /// The client as seen through `binding`: its IP, its prefix, a
+    /// User-Agent hash, or empty
+    #[serde(rename = "sub")]
+    pub subject: String,
+    #[serde(rename = "dom")]
+    pub domain: String,
+    /// Issued at, unix seconds
+    pub iat: u64,
+    /// Expires at, unix seconds
+    pub exp: u64,
+    /// Optional claims for future use; unknown entries are carried along
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub ext: BTreeMap<String, String>,
+}
+
+#[derive(Debug)]
+pub enum TokenError {
+    Malformed(String),
+    UnsupportedVersion(String),
+    UnknownKey(String),
+    BadSignature(MacError),
+    LegacyNotAccepted,
+    Expired,
+    /// Issued to a different client or domain
+    BindingMismatch,
+}
+
+impl TokenError {
+    /// Machine-readable reason, see [`crate::error::AppError::code`].
+    pub fn code(&self) -> &'static str {
+        match self {
+            TokenError::Malformed(_) => "invalid_token",
+            TokenError::UnsupportedVersion(_) => "unsupported_token_version",
+            TokenError::UnknownKey(_) => "unknown_key",
+            TokenError::BadSignature(_) => "invalid_signature",
+            TokenError::LegacyNotAccepted => "legacy_token",
+            TokenError::Expired => "expired",
+            TokenError::BindingMismatch => "binding_mismatch",
+        }
+    }
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenError::Malformed(reason) => write!(f, "malformed token: {}", reason),
+            TokenError::UnsupportedVersion(version) => {
+                write!(f, "unsupported token version {:?}", version)
+            }
+            TokenError::UnknownKey(kid) => write!(f, "unknown key id {:?}", kid),
+            TokenError::BadSignature(e) => write!(f, "invalid token signature: {}", e),
+            TokenError::LegacyNotAccepted => {
+                write!(f, "legacy v0 token outside the migration window")
+            }
+            TokenError::Expired => write!(f, "token has expired"),
+            TokenError::BindingMismatch => {
+                write!(f, "token was issued to a different client or domain")
+            }
+        }
+    }
+}
+
+impl std::error::Error for TokenError {}
+
+/// One on-the-wire token encoding. New formats are added by implementing
+/// this trait and registering them with [`TokenCodec`].
+pub trait TokenFormat: Send + Sync {
+    /// Version tag, e.g. `"v1"`. Tokens start with `"<version>."`, except
+    /// the untagged legacy format which reports `"v0"`.
+    fn version(&self) -> &'static str;
+
+    fn seal(&self, claims: &Claims, key: &Key) -> Result<String, TokenError>;
+
+    /// Checks the signature against `keys` and returns the claims. Expiry and
+    /// binding checks are left to the caller.
+    fn open(&self, token: &str, keys: &[Key]) -> Result<Claims, TokenError>;
+}
+
+/// The original `base64(ip|domain|expires):hexsig` format. It carries no key
+/// id, so every known key is tried, and it predates per-purpose keys, so it
+/// is signed with the raw master secret.
+pub struct LegacyV0;
+
+impl TokenFormat for LegacyV0 {
+    fn version(&self) -> &'static str {
+        "v0"
+    }
+
+    fn seal(&self, claims: &Claims, key: &Key) -> Result<String, TokenError> {
+        let payload = format!("{}|{}|{}", claims.subject, claims.domain, claims.exp);
+        let signature = hmac_hex(key.secret.as_bytes(), payload.as_bytes());
+        Ok(format!(
+            "{}:{}",
+            general_purpose::STANDARD.encode(payload.as_bytes()),
+            signature
+        ))
+    }
+
+    fn open(&self, token: &str, keys: &[Key]) -> Result<Claims, TokenError> {
+        // Parse token format: base64(payload):signature
+        let (payload_b64, provided_signature) = token
+            .split_once(':')
+            .ok_or_else(|| TokenError::Malformed("expected base64:signature".to_string()))?;
+
+        let payload_bytes = general_purpose::STANDARD
+            .decode(payload_b64)
+            .map_err(|e| TokenError::Malformed(e.to_string()))?;
+        let payload =
+            String::from_utf8(payload_bytes).map_err(|e| TokenError::Malformed(e.to_string()))?;
+
+        let mut result = Err(MacError::Mismatch);
+        for key in keys {
+            result = verify_hmac_hex(
+                key.secret.as_bytes(),
+                payload.as_bytes(),
+                provided_signature,
+            );
+            if result != Err(MacError::Mismatch) {
+                break;
+            }
+        }
+        result.map_err(TokenError::BadSignature)?;
+
+        // Parse payload: ip|domain|expires
+        let parts: Vec<&str> = payload.split('|').collect();
+        if parts.len() != 3 {
+            return Err(TokenError::Malformed(
+                "expected ip|domain|expires".to_string(),
+            ));
+        }
+        let exp = parts[2]
+            .parse()
+            .map_err(|_| TokenError::Malformed("invalid expiry".to_string()))?;
+
+        Ok(Claims {
+            binding: BindingPolicy::Exact,
+            subject: parts[0].to_string(),
+            domain: parts[1].to_string(),
+            iat: 0,
+            exp,
+            ext: BTreeMap::new(),
+        })
+    }
+}
+
+/// `v1.<kid>.<base64url(json claims)>.<hexsig>`, signed over everything
+/// before the last dot.
+pub struct V1;
+
+impl TokenFormat for V1 {
+    fn version(&self) -> &'static str {
+        "v1"
+    }
+
+    fn seal(&self, claims: &Claims, key: &Key) -> Result<String, TokenError> {
+        let json = serde_json::to_vec(claims).map_err(|e| TokenError::Malformed(e.to_string()))?;
+        let signed = format!(
+            "v1.{}.{}",
+            key.id,
+            general_purpose::URL_SAFE_NO_PAD.encode(json)
+        );
+        let signature = hmac_hex(key.token_key(), signed.as_bytes());
+        Ok(format!("{}.{}", signed, signature))
+    }
+
+    fn open(&self, token: &str, keys: &[Key]) -> Result<Claims, TokenError> {
+        let (signed, provided_signature) = token
+            .rsplit_once('.')
+            .ok_or_else(|| TokenError::Malformed("missing signature".to_string()))?;
+        let mut parts = signed.splitn(3, '.');
+        let (Some("v1"), Some(kid), Some(claims_b64)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(TokenError::Malformed(
+                "expected v1.kid.claims.sig".to_string(),
+            ));
+        };
+
+        let key = keys
+            .iter()
+            .find(|key| key.id == kid)
+            .ok_or_else(|| TokenError::UnknownKey(kid.to_string()))?;
+        verify_hmac_hex(key.token_key(), signed.as_bytes(), provided_signature)
+            .map_err(TokenError::BadSignature)?;
+
+        let json = general_purpose::URL_SAFE_NO_PAD
+            .decode(claims_b64)
+            .map_err(|e| TokenError::Malformed(e.to_string()))?;
+        serde_json::from_slice(&json).map_err(|e| TokenError::Malformed(e.to_string()))
+    }
+}
+
+/// Issues tokens in the current format and opens any registered format.
+pub struct TokenCodec {
+    current: Box<dyn TokenFormat>,
+    formats: Vec<Box<dyn TokenFormat>>,
+    /// Legacy v0 tokens are accepted until this unix time
+    legacy_until: Option<u64>,
+}
+
+impl TokenCodec {
+    pub fn new(legacy_until: Option<u64>) -> Self {
+        Self {
+            current: Box::new(V1),
+            formats: vec![Box::new(V1)],
+            legacy_until,
+        }
+    }
+
+    pub fn issue(&self, claims: &Claims, key: &Key) -> Result<String, TokenError> {
+        self.current.seal(claims, key)
+    }
+
+    pub fn open(&self, token: &str, keys: &[Key]) -> Result<Claims, TokenError> {
+        let tag = token.split_once('.').map(|(tag, _)| tag);
+        if let Some(format) = self.formats.iter().find(|f| Some(f.version()) == tag) {
+            return format.open(token, keys);
+        }
+        if let Some(tag) = tag.filter(|tag| is_version_tag(tag)) {
+            return Err(TokenError::UnsupportedVersion(tag.to_string()));
+        }
+
+        match self.legacy_until {
+            Some(until) if unix_now() <= until => LegacyV0.open(token, keys),
+            _ => Err(TokenError::LegacyNotAccepted),
+        }
+    }
+}
+
+fn is_version_tag(tag: &str) -> bool {
+    tag.len() > 1 && tag.starts_with('v') && tag[1..].bytes().all(|b| b.is_ascii_digit())
+}
+
+// 🔐 SECURITY: Create cryptographically signed verification tokens
+pub fn create_verification_token(
+    codec: &TokenCodec,
+    binding: BindingPolicy,
+    client_ip: &str,
+    user_agent: &str,
+    domain: &str,
+    key: &Key,
+    ttl_secs: u64,
+) -> Result<String, TokenError> {
+    let now = unix_now();
+    let claims = Claims {
+        binding,
+        subject: binding.subject(client_ip, user_agent),
+        domain: domain.to_string(),
+        iat: now,
+        exp: now.saturating_add(ttl_secs),
+        ext: BTreeMap::new(),
+    };
+    codec.issue(&claims, key)
+}
+
+// 🔍 SECURITY: Verify signed tokens cryptographically; returns the claims of
+// a token that passed every check
+pub fn verify_token(
+    codec: &TokenCodec,
+    token: &str,
+    client_ip: &str,
+    user_agent: &str,
+    domain: &str,
+    keys: &[Key],
+) -> Result<Claims, TokenError> {
+    let claims = codec.open(token, keys)?;
+
+    // Check expiration
+    if unix_now() > claims.exp {
+        return Err(TokenError::Expired);
+    }
+
+    // Check client and domain binding, under the policy the token was issued with
+    let subject = claims.binding.subject(client_ip, user_agent);
+    if claims.subject != subject || claims.domain != domain {
+        warn!(
+            binding = %claims.binding,
+            token_subject = %redact::ip(&claims.subject),
+            token_domain = %claims.domain,
+            subject = %redact::ip(&subject),
+            domain,
+            "Token binding mismatch"
+        );
+        return Err(TokenError::BindingMismatch);
+    }
+
+    Ok(claims) // All validations passed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::key;
+
+    const CLIENT_IP: &str = "192.0.2.10";
+    const DOMAIN: &str = "dataspace.princeton.edu";
+
+    fn claims(exp: u64) -> Claims {
+        Claims {
+            binding: BindingPolicy::Exact,
+            subject: CLIENT_IP.to_string(),
+            domain: DOMAIN.to_string(),
+            iat: unix_now(),
+            exp,
+            ext: BTreeMap::new(),
+        }
+    }
+
+    fn issue(ttl_secs: u64) -> String {
+        let codec = TokenCodec::new(None);
+        create_verification_token(
+            &codec,
+            BindingPolicy::Exact,
+            CLIENT_IP,
+            "",
+            DOMAIN,
+            &key(),
+            ttl_secs,
+        )
+        .unwrap()
+    }
+
+    fn verify(codec: &TokenCodec, token: &str, keys: &[Key]) -> Result<Claims, &'static str> {
+        verify_token(codec, token, CLIENT_IP, "", DOMAIN, keys).map_err(|e| e.code())
+    }
+
+    #[test]
+    fn v1_tokens_round_trip() {
+        let token = issue(60);
+        let parts: Vec<&str> = token.split('.').collect();
+        assert_eq!(parts.len(), 4);
+        assert_eq!(&parts[..2], ["v1", "test"]);
+        assert!(parts[2]
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_'));
+        assert!(parts[3].bytes().all(|b| b.is_ascii_hexdigit()));
+
+        let claims = verify(&TokenCodec::new(None), &token, &[key()]).unwrap();
+        assert_eq!(claims.subject, CLIENT_IP);
+        assert_eq!(claims.domain, DOMAIN);
+        assert_eq!(claims.exp, claims.iat + 60);
+    }
+
+    #[test]
+    fn tokens_are_rejected_with_their_code() {
+        let codec = TokenCodec::new(None);
+        let token = issue(60);
+        let other = Key::new("other", "0f0e0d0c0b0a09080706050403020100");
+
+        let tampered = {
+            let (signed, signature) = token.rsplit_once('.').unwrap();
+            let (prefix, _) = signed.rsplit_once('.').unwrap();
+            let mut forged = claims(unix_now() + 60);
+            forged.domain = "oar.princeton.edu".to_string();
+            let json =
+                general_purpose::URL_SAFE_NO_PAD.encode(serde_json::to_vec(&forged).unwrap());
+            format!("{}.{}.{}", prefix, json, signature)
+        };
+        let expired = codec.issue(&claims(unix_now() - 1), &key()).unwrap();
+
+        let cases = [
+            (token.replacen("v1.", "v2.", 1), "unsupported_token_version"),
+            (token.replacen("v1.test.", "v1.gone.", 1), "unknown_key"),
+            (tampered, "invalid_signature"),
+            (expired, "expired"),
+            ("v1.test".to_string(), "invalid_token"),
+        ];
+        for (token, code) in cases {
+            assert_eq!(
+                verify(&codec, &token, &[key()]).err(),
+                Some(code),
+                "{}",
+                token
+            );
+        }
+
+        // Signed by a key this server does not have
+        assert_eq!(verify(&codec, &token, &[other]).err(), Some("unknown_key"));
+    }
+
+    #[test]
+    fn legacy_tokens_are_accepted_only_inside_the_migration_window() {
+        let now = unix_now();
+        let legacy = LegacyV0.seal(&claims(now + 60), &key()).unwrap();
+
+        assert_eq!(
+            verify(&TokenCodec::new(None), &legacy, &[key()]).err(),
+            Some("legacy_token")
+        );
+        assert!(verify(&TokenCodec::new(Some(now + 60)), &legacy, &[key()]).is_ok());
+        assert_eq!(
+            verify(&TokenCodec::new(Some(now - 1)), &legacy, &[key()]).err(),
+            Some("legacy_token")
+        );
+    }
+}
@@ -0,0 +1,50 @@
+// Challenge page behaviour. Page strings are passed in by the template as
+// JSON so this file can be served as a static, cacheable script.
+const page = JSON.parse(document.getElementById('altcha-page').textContent);
+const strings = page.strings;
+const form = document.getElementById('challenge-form');
+const submitBtn = document.getElementById('submit-btn');
+const loading = document.getElementById('loading');
+const widget = document.querySelector('altcha-widget');
+
+// Show form when widget loads
+setTimeout(() => {
+    loading.style.display = 'none';
+    form.style.display = 'block';
+}, 1000);
+
+widget.addEventListener('statechange', (ev) => {
+    switch(ev.detail.state) {
+        case 'verified':
+            submitBtn.disabled = false;
+            submitBtn.textContent = strings.continue;
+            submitBtn.style.background = '#2e7d32';
+            break;
+        case 'error':
+            submitBtn.disabled = true;
+            submitBtn.textContent = strings.failed;
+            setTimeout(() => widget.reset(), 2000);
+            break;
+        case 'verifying':
+            submitBtn.disabled = true;
+            submitBtn.textContent = strings.verifying;
+            break;
+        case 'solving':
+            submitBtn.disabled = true;
+            submitBtn.textContent = strings.solving;
+            break;
+        default:
+            submitBtn.disabled = true;
+            submitBtn.textContent = strings.complete;
+            submitBtn.style.background = '#e87722';
+    }
+});
+
+form.addEventListener('submit', (e) => {
+    // The widget puts the solution in the form's "altcha" field; the server
+    // answers the plain form post with a redirect or the page and an error
+    if (!new FormData(form).get('altcha')) {
+        e.preventDefault();
+        alert(strings.incomplete);
+    }
+});